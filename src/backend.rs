@@ -0,0 +1,374 @@
+use ndarray::{Array, ArrayD, Ix4};
+use std::fmt::{Debug, Display};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::TaggerError;
+use crate::tagger::{Device, TensorInfo};
+
+#[cfg(feature = "ort")]
+mod onnxruntime;
+#[cfg(feature = "tract")]
+mod tract;
+
+#[cfg(feature = "ort")]
+pub use onnxruntime::OrtBackend;
+#[cfg(feature = "tract")]
+pub use tract::TractBackend;
+
+#[cfg(not(any(feature = "ort", feature = "tract")))]
+compile_error!("Enable the `ort` or `tract` feature to have an inference backend");
+
+/// Runtime that runs an ONNX model
+pub trait InferenceBackend: Debug + Send + Sync {
+    /// Inputs of the model
+    fn inputs(&self) -> &[TensorInfo];
+
+    /// Outputs of the model
+    fn outputs(&self) -> &[TensorInfo];
+
+    /// Run the model with the tensor as the input of the name, and get the output of the name.
+    fn run(
+        &self,
+        input: &str,
+        tensor: Array<f32, Ix4>,
+        output: &str,
+    ) -> Result<ArrayD<f32>, TaggerError>;
+
+    /// Device that serves the session, i.e. the one of the first execution provider registered
+    fn device(&self) -> Device {
+        Device::Cpu
+    }
+}
+
+/// Resolve the device that serves the session, registering the execution provider of each device
+/// in the order of preference. `register` returns whether the provider is available.
+/// The devices whose providers are not available are skipped, and the CPU serves the session
+/// if none is available. In the strict mode, the first device must serve the session.
+pub(crate) fn resolve_device<D: Clone + PartialEq + Display>(
+    devices: &[D],
+    cpu: D,
+    strict: bool,
+    mut register: impl FnMut(&D) -> Result<bool, TaggerError>,
+) -> Result<D, TaggerError> {
+    let mut resolved = None;
+    let mut unavailable = vec![];
+    for device in devices {
+        // the CPU is always available as the fallback, so it has no provider
+        let available = match *device == cpu {
+            true => true,
+            false => register(device)?,
+        };
+        match available {
+            true => {
+                resolved.get_or_insert_with(|| device.clone());
+            }
+            false => unavailable.push(device.to_string()),
+        }
+    }
+    let resolved = resolved.unwrap_or_else(|| cpu.clone());
+
+    let requested = devices.first().cloned().unwrap_or(cpu);
+    if strict && resolved != requested {
+        return Err(TaggerError::Backend(format!(
+            "{} is requested, but {} serves the session. Not available: {}",
+            requested,
+            resolved,
+            unavailable.join(", ")
+        )));
+    }
+    Ok(resolved)
+}
+
+/// Inference backend to load the models with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Backend {
+    /// ONNX Runtime, which needs the shared library of onnxruntime
+    #[cfg(feature = "ort")]
+    Ort,
+    /// tract, written in pure Rust and running on CPU only
+    #[cfg(feature = "tract")]
+    Tract,
+}
+
+impl Default for Backend {
+    /// ONNX Runtime if enabled, otherwise tract
+    fn default() -> Self {
+        #[cfg(feature = "ort")]
+        return Backend::Ort;
+
+        #[cfg(all(feature = "tract", not(feature = "ort")))]
+        return Backend::Tract;
+    }
+}
+
+impl Backend {
+    /// Load the ONNX model with the backend. The session options are used only by ONNX Runtime.
+    #[cfg_attr(not(feature = "ort"), allow(unused_variables))]
+    pub fn load<P: AsRef<Path>>(
+        &self,
+        model_path: P,
+        session: &SessionOptions,
+    ) -> Result<Box<dyn InferenceBackend>, TaggerError> {
+        match self {
+            #[cfg(feature = "ort")]
+            Backend::Ort => Ok(Box::new(OrtBackend::load(model_path, session)?)),
+            #[cfg(feature = "tract")]
+            Backend::Tract => {
+                // tract runs only on the CPU
+                resolve_device(&session.devices, Device::Cpu, session.strict_device, |_| {
+                    Ok(false)
+                })?;
+                Ok(Box::new(TractBackend::load(model_path)?))
+            }
+        }
+    }
+}
+
+/// Graph optimization level of ONNX Runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum OptimizationLevel {
+    /// No graph optimization
+    Disable,
+    /// Removal of the redundant nodes and constant folding
+    Basic,
+    /// Complex node fusions in addition to the basic ones
+    Extended,
+    /// Layout optimizations in addition to the extended ones
+    All,
+}
+
+impl FromStr for OptimizationLevel {
+    type Err = TaggerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "disable" => Ok(OptimizationLevel::Disable),
+            "basic" => Ok(OptimizationLevel::Basic),
+            "extended" => Ok(OptimizationLevel::Extended),
+            "all" => Ok(OptimizationLevel::All),
+            _ => Err(TaggerError::Backend(format!(
+                "Unknown graph optimization level: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Options of the inference session of ONNX Runtime. `None` keeps the default of ONNX Runtime.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionOptions {
+    /// Devices to run the model on in the order of preference. The CPU if empty.
+    pub devices: Vec<Device>,
+    /// Fail to load the model if the first device does not serve the session,
+    /// instead of falling back to the next device
+    pub strict_device: bool,
+    /// Threads to run each node with
+    pub intra_threads: Option<usize>,
+    /// Threads to run the independent nodes in parallel. Enables the parallel execution if set.
+    pub inter_threads: Option<usize>,
+    /// Graph optimization level
+    pub optimization_level: Option<OptimizationLevel>,
+    /// Whether to preallocate the memory by the pattern of the first run
+    pub memory_pattern: Option<bool>,
+    /// Whether to use the memory arena of the CPU
+    pub memory_arena: Option<bool>,
+    /// Run the same input to the same output, on a single thread in sequence.
+    /// Takes precedence over the numbers of threads.
+    pub deterministic: bool,
+    /// Path to save the optimized model to, which can be loaded later with the optimization disabled
+    pub optimized_model_path: Option<PathBuf>,
+}
+
+impl SessionOptions {
+    /// Read the options from `TAGGER_STRICT_DEVICE`, `TAGGER_INTRA_THREADS`, `TAGGER_INTER_THREADS`, `TAGGER_GRAPH_OPTIMIZATION`,
+    /// `TAGGER_MEMORY_PATTERN`, `TAGGER_MEMORY_ARENA`, `TAGGER_DETERMINISTIC` and `TAGGER_OPTIMIZED_MODEL`.
+    pub fn from_env() -> Result<Self, TaggerError> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, TaggerError> {
+        let var = |key: &str| var(key).filter(|value| !value.is_empty());
+        let threads = |key: &str| {
+            var(key)
+                .map(|value| {
+                    value.parse::<usize>().map_err(|_| {
+                        TaggerError::Backend(format!("{} must be a number: {}", key, value))
+                    })
+                })
+                .transpose()
+        };
+        let flag = |key: &str| {
+            var(key).map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"))
+        };
+
+        Ok(Self {
+            devices: Vec::new(),
+            strict_device: flag("TAGGER_STRICT_DEVICE").unwrap_or(false),
+            intra_threads: threads("TAGGER_INTRA_THREADS")?,
+            inter_threads: threads("TAGGER_INTER_THREADS")?,
+            optimization_level: var("TAGGER_GRAPH_OPTIMIZATION")
+                .map(|value| value.parse())
+                .transpose()?,
+            memory_pattern: flag("TAGGER_MEMORY_PATTERN"),
+            memory_arena: flag("TAGGER_MEMORY_ARENA"),
+            deterministic: flag("TAGGER_DETERMINISTIC").unwrap_or(false),
+            optimized_model_path: var("TAGGER_OPTIMIZED_MODEL").map(PathBuf::from),
+        })
+    }
+
+    /// Set the devices to run the model on
+    pub fn with_devices(mut self, devices: Vec<Device>) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// Set the devices if none is set yet
+    pub(crate) fn or_devices(mut self, devices: &[Device]) -> Self {
+        if self.devices.is_empty() {
+            self.devices = devices.to_vec();
+        }
+        self
+    }
+
+    /// Set the strict mode, failing to load the model if the first device does not serve the session
+    pub fn with_strict_device(mut self, strict: bool) -> Self {
+        self.strict_device = strict;
+        self
+    }
+
+    /// Set the number of threads to run each node with
+    pub fn with_intra_threads(mut self, threads: usize) -> Self {
+        self.intra_threads = Some(threads);
+        self
+    }
+
+    /// Set the number of threads to run the independent nodes in parallel
+    pub fn with_inter_threads(mut self, threads: usize) -> Self {
+        self.inter_threads = Some(threads);
+        self
+    }
+
+    /// Set the graph optimization level
+    pub fn with_optimization_level(mut self, level: OptimizationLevel) -> Self {
+        self.optimization_level = Some(level);
+        self
+    }
+
+    /// Set whether to preallocate the memory by the pattern of the first run
+    pub fn with_memory_pattern(mut self, enable: bool) -> Self {
+        self.memory_pattern = Some(enable);
+        self
+    }
+
+    /// Set whether to use the memory arena of the CPU
+    pub fn with_memory_arena(mut self, enable: bool) -> Self {
+        self.memory_arena = Some(enable);
+        self
+    }
+
+    /// Set the deterministic compute
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Set the path to save the optimized model to
+    pub fn with_optimized_model_path(mut self, path: PathBuf) -> Self {
+        self.optimized_model_path = Some(path);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn session_options(vars: &[(&str, &str)]) -> Result<SessionOptions, TaggerError> {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        SessionOptions::from_vars(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_session_options_from_vars() {
+        assert_eq!(session_options(&[]).unwrap(), SessionOptions::default());
+
+        let options = session_options(&[
+            ("TAGGER_INTRA_THREADS", "16"),
+            ("TAGGER_INTER_THREADS", ""),
+            ("TAGGER_GRAPH_OPTIMIZATION", "Extended"),
+            ("TAGGER_MEMORY_PATTERN", "false"),
+            ("TAGGER_MEMORY_ARENA", "1"),
+            ("TAGGER_OPTIMIZED_MODEL", "model.opt.onnx"),
+        ])
+        .unwrap();
+        assert_eq!(
+            options,
+            SessionOptions::default()
+                .with_intra_threads(16)
+                .with_optimization_level(OptimizationLevel::Extended)
+                .with_memory_pattern(false)
+                .with_memory_arena(true)
+                .with_optimized_model_path(PathBuf::from("model.opt.onnx"))
+        );
+
+        // the default of ONNX Runtime unless set
+        assert_eq!(SessionOptions::default().memory_arena, None);
+        let options = session_options(&[("TAGGER_MEMORY_ARENA", "false")]).unwrap();
+        assert_eq!(options.memory_arena, Some(false));
+
+        assert!(session_options(&[("TAGGER_INTRA_THREADS", "many")]).is_err());
+        assert!(session_options(&[("TAGGER_GRAPH_OPTIMIZATION", "max")]).is_err());
+    }
+
+    #[test]
+    fn test_session_devices() {
+        let options = SessionOptions::default();
+        assert_eq!(options.or_devices(&Device::cpu()).devices, Device::cpu());
+
+        // the devices of the session take precedence
+        let options = SessionOptions::default().with_devices(Device::cpu());
+        assert_eq!(options.or_devices(&[]).devices, Device::cpu());
+    }
+
+    #[test]
+    fn test_resolve_device() {
+        // stub of the providers available in ONNX Runtime
+        let available = ["CUDA"];
+        let register = |device: &&str| Ok(available.contains(device));
+
+        let devices = ["TensorRT", "CUDA", "CPU"];
+        assert_eq!(
+            resolve_device(&devices, "CPU", false, register).unwrap(),
+            "CUDA"
+        );
+        assert!(resolve_device(&devices, "CPU", true, register).is_err());
+        assert_eq!(
+            resolve_device(&devices[1..], "CPU", true, register).unwrap(),
+            "CUDA"
+        );
+
+        // falling back to the CPU
+        assert_eq!(
+            resolve_device(&["TensorRT"], "CPU", false, register).unwrap(),
+            "CPU"
+        );
+        assert!(resolve_device(&["TensorRT"], "CPU", true, register).is_err());
+        assert_eq!(resolve_device(&[], "CPU", true, register).unwrap(), "CPU");
+
+        // the errors of the registration are not fallen back from
+        let failing = |_: &&str| Err(TaggerError::Cuda("out of memory".to_string()));
+        assert!(resolve_device(&devices, "CPU", false, failing).is_err());
+
+        assert_eq!(
+            resolve_device(&Device::cpu(), Device::Cpu, true, |_| Ok(false)).unwrap(),
+            Device::Cpu
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use ndarray::{Array, ArrayD, Ix4};
+use ort::{
+    CPUExecutionProvider, ExecutionProvider, GraphOptimizationLevel, Session, SessionBuilder,
+    TensorElementType, ValueType,
+};
+use std::path::Path;
+
+#[cfg(feature = "cuda")]
+use ort::CUDAExecutionProvider;
+
+#[cfg(feature = "tensorrt")]
+use ort::TensorRTExecutionProvider;
+
+use super::{resolve_device, InferenceBackend, OptimizationLevel, SessionOptions};
+use crate::error::TaggerError;
+use crate::tagger::{DataType, Device, TensorInfo};
+
+/// Execution provider of the device. The CPU is always available as the fallback, so it has none.
+fn execution_provider(device: &Device) -> Option<Box<dyn ExecutionProvider>> {
+    match device {
+        Device::Cpu => None,
+        #[cfg(feature = "cuda")]
+        Device::Cuda => Some(Box::new(CUDAExecutionProvider::default())),
+        #[cfg(feature = "cuda")]
+        Device::CudaDevice(device_id) => {
+            let provider = CUDAExecutionProvider::default();
+            Some(Box::new(provider.with_device_id(*device_id)))
+        }
+        #[cfg(feature = "tensorrt")]
+        Device::TensorRT => Some(Box::new(TensorRTExecutionProvider::default())),
+        #[cfg(feature = "tensorrt")]
+        Device::TensorRTDevice(device_id) => {
+            let provider = TensorRTExecutionProvider::default();
+            Some(Box::new(provider.with_device_id(*device_id)))
+        }
+    }
+}
+
+/// Register the execution providers of the devices that ONNX Runtime is built with,
+/// and get the device that serves the session.
+fn register_devices(
+    builder: &SessionBuilder,
+    options: &SessionOptions,
+) -> Result<Device, TaggerError> {
+    resolve_device(
+        &options.devices,
+        Device::Cpu,
+        options.strict_device,
+        |device| {
+            let Some(provider) = execution_provider(device) else {
+                return Ok(true);
+            };
+            let available = provider
+                .is_available()
+                .map_err(|e| TaggerError::Cuda(e.to_string()))?;
+            if available {
+                provider
+                    .register(builder)
+                    .map_err(|e| TaggerError::Cuda(format!("{}: {}", device, e)))?;
+            }
+            Ok(available)
+        },
+    )
+}
+
+/// Backend of ONNX Runtime
+#[derive(Debug)]
+pub struct OrtBackend {
+    session: Session,
+    device: Device,
+    inputs: Vec<TensorInfo>,
+    outputs: Vec<TensorInfo>,
+}
+
+impl OrtBackend {
+    /// Load the model using the local file path with the session options
+    pub fn load<P: AsRef<Path>>(
+        model_path: P,
+        options: &SessionOptions,
+    ) -> Result<Self, TaggerError> {
+        let builder = match Session::builder() {
+            Ok(builder) => builder,
+            Err(e) => return Err(TaggerError::Ort(e.to_string())),
+        };
+        let device = register_devices(&builder, options)?;
+        let builder = configure(builder, options).map_err(|e| TaggerError::Ort(e.to_string()))?;
+
+        let session = builder
+            .commit_from_file(model_path)
+            .map_err(|e| TaggerError::Ort(e.to_string()))?;
+
+        let inputs = session
+            .inputs
+            .iter()
+            .map(|input| tensor_info(&input.name, &input.input_type))
+            .collect();
+        let outputs = session
+            .outputs
+            .iter()
+            .map(|output| tensor_info(&output.name, &output.output_type))
+            .collect();
+
+        Ok(Self {
+            session,
+            device,
+            inputs,
+            outputs,
+        })
+    }
+}
+
+impl InferenceBackend for OrtBackend {
+    fn inputs(&self) -> &[TensorInfo] {
+        &self.inputs
+    }
+
+    fn outputs(&self) -> &[TensorInfo] {
+        &self.outputs
+    }
+
+    fn run(
+        &self,
+        input: &str,
+        tensor: Array<f32, Ix4>,
+        output: &str,
+    ) -> Result<ArrayD<f32>, TaggerError> {
+        let inputs = ort::inputs![input => tensor].map_err(|e| TaggerError::Ort(e.to_string()))?;
+        let outputs = self
+            .session
+            .run(inputs)
+            .map_err(|e| TaggerError::Ort(e.to_string()))?;
+        let value = outputs
+            .get(output)
+            .ok_or_else(|| TaggerError::Ort(format!("The model has no output named {}", output)))?;
+        let tensor = value
+            .try_extract_tensor::<f32>()
+            .map_err(|e| TaggerError::Ort(e.to_string()))?;
+
+        Ok(tensor.into_owned())
+    }
+
+    fn device(&self) -> Device {
+        self.device.clone()
+    }
+}
+
+fn configure(mut builder: SessionBuilder, options: &SessionOptions) -> ort::Result<SessionBuilder> {
+    // the CPU provider disables the arena unless it is enabled, so it is registered only if set
+    if let Some(enable) = options.memory_arena {
+        let cpu = match enable {
+            true => CPUExecutionProvider::default().with_arena_allocator(),
+            false => CPUExecutionProvider::default(),
+        };
+        builder = builder.with_execution_providers([cpu.build()])?;
+    }
+
+    if options.deterministic {
+        // ONNX Runtime splits the work of a node differently by the number of threads,
+        // which changes the order of the floating point operations
+        builder = builder
+            .with_intra_threads(1)?
+            .with_parallel_execution(false)?;
+    } else {
+        if let Some(threads) = options.intra_threads {
+            builder = builder.with_intra_threads(threads)?;
+        }
+        if let Some(threads) = options.inter_threads {
+            builder = builder
+                .with_parallel_execution(true)?
+                .with_inter_threads(threads)?;
+        }
+    }
+    if let Some(level) = options.optimization_level {
+        builder = builder.with_optimization_level(match level {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+            OptimizationLevel::All => GraphOptimizationLevel::Level3,
+        })?;
+    }
+    if let Some(enable) = options.memory_pattern {
+        builder = builder.with_memory_pattern(enable)?;
+    }
+    if let Some(path) = &options.optimized_model_path {
+        builder = builder.with_optimized_model_path(path.to_string_lossy())?;
+    }
+
+    Ok(builder)
+}
+
+fn tensor_info(name: &str, value_type: &ValueType) -> TensorInfo {
+    TensorInfo {
+        name: name.to_string(),
+        dtype: value_type.tensor_type().map(|ty| match ty {
+            TensorElementType::Float32 => DataType::Float32,
+            other => DataType::Other(format!("{:?}", other)),
+        }),
+        dimensions: value_type.tensor_dimensions().cloned().unwrap_or_default(),
+    }
+}
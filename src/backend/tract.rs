@@ -0,0 +1,114 @@
+use ndarray::{Array, ArrayD, Ix4, IxDyn};
+use std::path::Path;
+use tract_onnx::prelude::*;
+
+use super::InferenceBackend;
+use crate::error::TaggerError;
+use crate::tagger::{DataType, TensorInfo};
+
+/// Backend of tract, which runs the model in pure Rust on CPU
+#[derive(Debug)]
+pub struct TractBackend {
+    plan: TypedRunnableModel<TypedModel>,
+    inputs: Vec<TensorInfo>,
+    outputs: Vec<TensorInfo>,
+}
+
+impl TractBackend {
+    /// Load and optimize the model using the local file path
+    pub fn load<P: AsRef<Path>>(model_path: P) -> Result<Self, TaggerError> {
+        let model = tract_onnx::onnx()
+            .model_for_path(model_path)
+            .and_then(|model| model.into_optimized())
+            .map_err(backend_error)?;
+
+        let inputs = model
+            .input_outlets()
+            .and_then(|outlets| outlets.iter().map(|o| tensor_info(&model, *o)).collect())
+            .map_err(backend_error)?;
+        let outputs = model
+            .output_outlets()
+            .and_then(|outlets| outlets.iter().map(|o| tensor_info(&model, *o)).collect())
+            .map_err(backend_error)?;
+
+        let plan = model.into_runnable().map_err(backend_error)?;
+
+        Ok(Self {
+            plan,
+            inputs,
+            outputs,
+        })
+    }
+}
+
+impl InferenceBackend for TractBackend {
+    fn inputs(&self) -> &[TensorInfo] {
+        &self.inputs
+    }
+
+    fn outputs(&self) -> &[TensorInfo] {
+        &self.outputs
+    }
+
+    fn run(
+        &self,
+        input: &str,
+        tensor: Array<f32, Ix4>,
+        output: &str,
+    ) -> Result<ArrayD<f32>, TaggerError> {
+        if self.inputs.len() != 1 || self.inputs[0].name != input {
+            return Err(TaggerError::Backend(format!(
+                "The tract backend runs the models with the only input {}",
+                input
+            )));
+        }
+        let index = self
+            .outputs
+            .iter()
+            .position(|info| info.name == output)
+            .ok_or_else(|| {
+                TaggerError::Backend(format!("The model has no output named {}", output))
+            })?;
+
+        // tract has its own version of ndarray, so the tensors are passed as the raw values
+        let shape = tensor.shape().to_vec();
+        let values = tensor.iter().copied().collect::<Vec<_>>();
+        let tensor = Tensor::from_shape(&shape, &values).map_err(backend_error)?;
+
+        let outputs = self
+            .plan
+            .run(tvec!(tensor.into_tvalue()))
+            .map_err(backend_error)?;
+        let value = &outputs[index];
+        let values = value.as_slice::<f32>().map_err(backend_error)?;
+
+        ArrayD::from_shape_vec(IxDyn(value.shape()), values.to_vec())
+            .map_err(|e| TaggerError::Backend(e.to_string()))
+    }
+}
+
+fn tensor_info(model: &TypedModel, outlet: OutletId) -> TractResult<TensorInfo> {
+    let fact = model.outlet_fact(outlet)?;
+    let name = model
+        .outlet_label(outlet)
+        .unwrap_or(&model.node(outlet.node).name);
+    let dtype = match fact.datum_type {
+        DatumType::F32 => DataType::Float32,
+        other => DataType::Other(format!("{:?}", other)),
+    };
+    let dimensions = fact
+        .shape
+        .iter()
+        .map(|dim| dim.to_i64().unwrap_or(-1))
+        .collect();
+
+    Ok(TensorInfo {
+        name: name.to_string(),
+        dtype: Some(dtype),
+        dimensions,
+    })
+}
+
+fn backend_error(e: TractError) -> TaggerError {
+    TaggerError::Backend(e.to_string())
+}
@@ -0,0 +1,333 @@
+use ndarray::{Array, ArrayView, Axis, Ix4, Slice};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crate::error::TaggerError;
+
+/// Messages of the errors of ONNX Runtime, CUDA and cuDNN when the memory runs out
+const ALLOCATION_ERRORS: &[&str] = &[
+    "failed to allocate",
+    "out of memory",
+    "bad_alloc",
+    "bad allocation",
+    "alloc_failed",
+];
+
+/// Whether the error is a failure to allocate the memory of the device
+pub fn is_allocation_error(error: &TaggerError) -> bool {
+    let message = error.to_string().to_lowercase();
+    ALLOCATION_ERRORS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+/// Batching that adapts the batch size to the memory of the device.
+///
+/// The batches are split into the largest ones under the memory budget. With the probe,
+/// the largest batch size that fits in the memory of the device is found on the first batch.
+/// A failing batch is retried at half size down to the individual images, so that only
+/// the images that fail on their own get an error. When a batch fails to allocate the memory,
+/// the batch size is kept at half size for the following batches.
+#[derive(Debug)]
+pub struct AdaptiveBatcher {
+    max_batch_size: usize,
+    /// Memory budget of a batch in bytes
+    memory_budget: Option<u64>,
+    /// Estimated memory of an image in a batch in bytes, the size of its input tensor if `None`
+    memory_per_image: Option<u64>,
+    /// Whether to probe the largest batch size on the first batch
+    probe: bool,
+    /// Batch size found by the probe
+    probed: OnceLock<usize>,
+    /// Largest batch size that has not failed to allocate the memory
+    limit: AtomicUsize,
+}
+
+impl AdaptiveBatcher {
+    /// Create a new batcher of batches of up to `max_batch_size` images.
+    pub fn new(max_batch_size: usize) -> Self {
+        let max_batch_size = max_batch_size.max(1);
+        Self {
+            max_batch_size,
+            memory_budget: None,
+            memory_per_image: None,
+            probe: false,
+            probed: OnceLock::new(),
+            limit: AtomicUsize::new(max_batch_size),
+        }
+    }
+
+    /// Create a new batcher with the same settings, without the batch size learned by this one,
+    /// e.g. for another model.
+    pub fn fresh(&self) -> Self {
+        Self {
+            max_batch_size: self.max_batch_size,
+            memory_budget: self.memory_budget,
+            memory_per_image: self.memory_per_image,
+            probe: self.probe,
+            probed: OnceLock::new(),
+            limit: AtomicUsize::new(self.max_batch_size),
+        }
+    }
+
+    /// Set the memory budget of a batch in bytes.
+    pub fn with_memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Set the estimated memory of an image in a batch in bytes,
+    /// e.g. measured with the activations of the model.
+    pub fn with_memory_per_image(mut self, bytes: u64) -> Self {
+        self.memory_per_image = Some(bytes);
+        self
+    }
+
+    /// Set whether to probe the largest batch size that fits in the memory on the first batch,
+    /// by bisecting with the batches of its first image repeated. The outputs of the probe
+    /// are discarded, so it costs a few more runs of the model once.
+    pub fn with_probe(mut self, probe: bool) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Maximum number of images in a batch
+    pub fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+
+    /// Largest batch size that has not failed to allocate the memory.
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Largest batch size of the images of the input size under the memory budget.
+    pub fn batch_size(&self, input_bytes: u64) -> usize {
+        let by_budget = match self.memory_budget {
+            Some(budget) => {
+                let per_image = self.memory_per_image.unwrap_or(input_bytes).max(1);
+                (budget / per_image) as usize
+            }
+            None => self.max_batch_size,
+        };
+        self.max_batch_size.min(self.limit()).min(by_budget).max(1)
+    }
+
+    /// Run the model on the batch of images in batches that fit in the memory,
+    /// and get the output, or the error, of each image in order.
+    pub fn run<T>(
+        &self,
+        tensor: Array<f32, Ix4>,
+        infer: impl Fn(Array<f32, Ix4>) -> Result<Vec<T>, TaggerError>,
+    ) -> Vec<Result<T, TaggerError>> {
+        let count = tensor.len_of(Axis(0));
+        if count == 0 {
+            return vec![];
+        }
+        let input_bytes = (tensor.len() / count * std::mem::size_of::<f32>()) as u64;
+
+        if self.probe {
+            self.probed.get_or_init(|| {
+                let image = tensor.slice_axis(Axis(0), Slice::from(..1));
+                let size = self.probe(image, self.batch_size(input_bytes), &infer);
+                self.limit.fetch_min(size, Ordering::Relaxed);
+                size
+            });
+        }
+
+        let mut outputs = Vec::with_capacity(count);
+        let mut start = 0;
+        while start < count {
+            let end = count.min(start + self.batch_size(input_bytes));
+            let batch = tensor.slice_axis(Axis(0), Slice::from(start..end));
+            outputs.extend(self.run_batch(batch, &infer));
+            start = end;
+        }
+        outputs
+    }
+
+    /// Find the largest batch size up to `size` that does not fail to allocate the memory,
+    /// by bisecting with the batches of the image repeated.
+    fn probe<T>(
+        &self,
+        image: ArrayView<f32, Ix4>,
+        size: usize,
+        infer: &impl Fn(Array<f32, Ix4>) -> Result<Vec<T>, TaggerError>,
+    ) -> usize {
+        let (_, height, width, channels) = image.dim();
+        let fits = |size: usize| {
+            let batch = match image.broadcast((size, height, width, channels)) {
+                Some(batch) => batch.to_owned(),
+                None => return true,
+            };
+            // the other errors are of the images, and are left to the batches
+            !matches!(infer(batch), Err(e) if is_allocation_error(&e))
+        };
+
+        if size <= 1 || fits(size) {
+            return size;
+        }
+        let (mut fit, mut fail) = (0, size);
+        while fail - fit > 1 {
+            let mid = (fit + fail) / 2;
+            match fits(mid) {
+                true => fit = mid,
+                false => fail = mid,
+            }
+        }
+        fit.max(1)
+    }
+
+    /// Run the model on the batch, retrying at half size if it fails.
+    fn run_batch<T>(
+        &self,
+        batch: ArrayView<f32, Ix4>,
+        infer: &impl Fn(Array<f32, Ix4>) -> Result<Vec<T>, TaggerError>,
+    ) -> Vec<Result<T, TaggerError>> {
+        let size = batch.len_of(Axis(0));
+        let error = match infer(batch.to_owned()) {
+            Ok(outputs) if outputs.len() == size => return outputs.into_iter().map(Ok).collect(),
+            Ok(outputs) => TaggerError::Processor(format!(
+                "The model returned {} outputs for {} images",
+                outputs.len(),
+                size
+            )),
+            Err(e) => e,
+        };
+        if size == 1 {
+            return vec![Err(error)];
+        }
+
+        let half = size / 2;
+        if is_allocation_error(&error) {
+            self.limit.fetch_min(half, Ordering::Relaxed);
+        }
+        let mut outputs = self.run_batch(batch.slice_axis(Axis(0), Slice::from(..half)), infer);
+        outputs.extend(self.run_batch(batch.slice_axis(Axis(0), Slice::from(half..)), infer));
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Tensor of the images whose pixels are their indices
+    fn images(count: usize) -> Array<f32, Ix4> {
+        Array::from_shape_fn((count, 2, 2, 3), |(idx, _, _, _)| idx as f32)
+    }
+
+    /// Model that returns the index of each image,
+    /// and fails to allocate the memory for more than `capacity` images
+    fn model(
+        capacity: usize,
+        sizes: &Mutex<Vec<usize>>,
+    ) -> impl Fn(Array<f32, Ix4>) -> Result<Vec<usize>, TaggerError> + '_ {
+        move |tensor| {
+            let size = tensor.len_of(Axis(0));
+            sizes.lock().unwrap().push(size);
+            if size > capacity {
+                return Err(TaggerError::Ort(format!(
+                    "Failed to allocate memory for requested buffer of size {}",
+                    size * 1024
+                )));
+            }
+            Ok(tensor
+                .axis_iter(Axis(0))
+                .map(|image| image[[0, 0, 0]] as usize)
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_allocation_error() {
+        assert!(is_allocation_error(&TaggerError::Ort(
+            "CUDA failure 2: out of memory".to_string()
+        )));
+        assert!(!is_allocation_error(&TaggerError::Ort(
+            "Invalid rank for input".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_split_by_memory_budget() {
+        // 48 bytes of an image
+        let batcher = AdaptiveBatcher::new(16).with_memory_budget(48 * 4);
+        assert_eq!(batcher.batch_size(48), 4);
+        assert_eq!(batcher.batch_size(48 * 100), 1);
+
+        let sizes = Mutex::new(vec![]);
+        let outputs = batcher.run(images(10), model(16, &sizes));
+        let outputs = outputs.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(outputs, (0..10).collect::<Vec<_>>());
+        assert_eq!(*sizes.lock().unwrap(), vec![4, 4, 2]);
+
+        let batcher = AdaptiveBatcher::new(16)
+            .with_memory_budget(1000)
+            .with_memory_per_image(500);
+        assert_eq!(batcher.batch_size(48), 2);
+    }
+
+    #[test]
+    fn test_retry_at_half_size() {
+        let batcher = AdaptiveBatcher::new(8);
+        let sizes = Mutex::new(vec![]);
+        let outputs = batcher.run(images(12), model(3, &sizes));
+        let outputs = outputs.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(outputs, (0..12).collect::<Vec<_>>());
+        // 8 fails, then 4 fails, then the batches of 2 fit, and the limit is kept at 2
+        assert_eq!(*sizes.lock().unwrap(), vec![8, 4, 2, 2, 4, 2, 2, 2, 2]);
+        assert_eq!(batcher.limit(), 2);
+    }
+
+    #[test]
+    fn test_probe() {
+        let batcher = AdaptiveBatcher::new(16).with_probe(true);
+        let sizes = Mutex::new(vec![]);
+        let outputs = batcher.run(images(12), model(5, &sizes));
+        let outputs = outputs.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(outputs, (0..12).collect::<Vec<_>>());
+        // bisected between 0 and 16, then the batches of the probed size
+        assert_eq!(*sizes.lock().unwrap(), vec![16, 8, 4, 6, 5, 5, 5, 2]);
+        assert_eq!(batcher.limit(), 5);
+
+        // probed only once
+        sizes.lock().unwrap().clear();
+        batcher.run(images(5), model(5, &sizes));
+        assert_eq!(*sizes.lock().unwrap(), vec![5]);
+
+        // a new batcher probes again
+        let batcher = batcher.fresh();
+        assert_eq!(batcher.limit(), 16);
+        sizes.lock().unwrap().clear();
+        batcher.run(images(1), model(16, &sizes));
+        assert_eq!(*sizes.lock().unwrap(), vec![16, 1]);
+    }
+
+    #[test]
+    fn test_fail_individual_images() {
+        let batcher = AdaptiveBatcher::new(4);
+        let sizes = Mutex::new(vec![]);
+        let outputs = batcher.run(images(3), model(0, &sizes));
+        assert_eq!(outputs.len(), 3);
+        assert!(outputs.iter().all(|output| output.is_err()));
+        assert_eq!(batcher.limit(), 1);
+
+        // an error other than the allocation fails only the bad image
+        let batcher = AdaptiveBatcher::new(4);
+        let outputs = batcher.run(images(4), |tensor| {
+            let indices = tensor
+                .axis_iter(Axis(0))
+                .map(|image| image[[0, 0, 0]] as usize)
+                .collect::<Vec<_>>();
+            match indices.contains(&2) {
+                true => Err(TaggerError::Ort("Invalid input".to_string())),
+                false => Ok(indices),
+            }
+        });
+        assert!(outputs[2].is_err());
+        assert_eq!(outputs[3].as_ref().unwrap(), &3);
+        assert_eq!(batcher.limit(), 4);
+    }
+}
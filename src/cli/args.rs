@@ -1,16 +1,74 @@
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::file::{build_globs, WalkOptions};
+use clap::{builder::RangedU64ValueParser, Args, Parser, Subcommand, ValueEnum};
+use wdtagger::backend::{Backend, OptimizationLevel, SessionOptions};
+use wdtagger::batch::AdaptiveBatcher;
+use wdtagger::embedding::EmbeddingFormat;
+use wdtagger::file::HfOptions;
+use wdtagger::preset::{ModelPreset, V2Model, V3Model};
+use wdtagger::processor::{ChannelOrder, Layout, PreprocessOptions, ValueRange};
+use wdtagger::registry::LoadOptions;
+use wdtagger::tagger::{Device, TensorNames};
+use wdtagger::tags::TagCategory;
+use wdtagger::threshold::{Threshold, DEFAULT_CHARACTER_THRESHOLD, DEFAULT_GENERAL_THRESHOLD};
 
 #[derive(Parser, Debug, Clone)]
-#[command(version, about, long_about = None)]
+#[command(name = "tagger", version, about, long_about = None)]
 #[command(propagate_version = false)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
     /// Input and output options
     #[command(flatten)]
     pub io: InputOutput,
 
-    /// Model version
+    /// Model version, or the server mode
     #[command(subcommand)]
-    pub model: Option<ModelVersion>,
+    pub command: Option<Command>,
+
+    /// Revision (branch, tag or commit hash) of the model repository
+    #[arg(long)]
+    pub revision: Option<String>,
+
+    /// Load the model files from a local folder instead of Hugging Face
+    #[arg(long)]
+    pub model_dir: Option<String>,
+
+    /// Use only the Hugging Face cache without network access [env: HF_HUB_OFFLINE]
+    #[arg(long)]
+    pub offline: bool,
+
+    /// Endpoint URL of the Hugging Face Hub [env: HF_ENDPOINT]
+    #[arg(long)]
+    pub hf_endpoint: Option<String>,
+
+    /// Access token for the Hugging Face Hub [env: HF_TOKEN]
+    #[arg(long)]
+    pub hf_token: Option<String>,
+
+    /// Cache directory of the Hugging Face Hub [env: HF_HUB_CACHE]
+    #[arg(long)]
+    pub hf_cache_dir: Option<String>,
+
+    /// Extract the embeddings of the images into a file per image in this format, instead of the tags.
+    /// The files are written like the caption files.
+    #[arg(long)]
+    pub embeddings: Option<EmbeddingFormat>,
+
+    /// Output of the model to read the features from [default: the output other than the tag probabilities]
+    #[arg(long, requires = "embeddings")]
+    pub embedding_output: Option<String>,
+
+    /// Local ONNX model that returns the features, e.g. the tagger with its graph edited,
+    /// instead of the tagger model
+    #[arg(long, requires = "embeddings")]
+    pub embedding_model: Option<String>,
+
+    /// Inference backend to run the model with
+    #[arg(long, value_enum, default_value_t)]
+    pub backend: Backend,
+
+    /// Options of the session of ONNX Runtime
+    #[command(flatten)]
+    pub session: Session,
 
     /// Inference device
     #[cfg(any(feature = "cuda", feature = "tensorrt"))]
@@ -18,6 +76,15 @@ pub struct Cli {
     pub devices: Vec<i32>,
 }
 
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    #[command(flatten)]
+    Model(ModelVersion),
+    /// Serve the tagging pipeline as an HTTP API
+    #[cfg(feature = "server")]
+    Serve(ServeArgs),
+}
+
 #[derive(Debug, Clone, Subcommand)]
 pub enum ModelVersion {
     /// Use the tagger model of v2 series
@@ -34,7 +101,45 @@ pub enum ModelVersion {
     },
     /// Use a custom model with the specified parameters
     #[command(name = "--custom")]
-    Custom(CustomModel),
+    Custom(Box<CustomModel>),
+}
+
+#[cfg(feature = "server")]
+#[derive(Args, Clone, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: String,
+
+    /// Port to listen on
+    #[arg(short, long, default_value = "8080")]
+    pub port: u16,
+
+    /// Maximum number of images tagged at once
+    #[arg(long, default_value = "16", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_batch_size: usize,
+
+    /// Time in milliseconds to wait for more images before tagging a batch
+    #[arg(long, default_value = "5")]
+    pub batch_wait_ms: u64,
+
+    /// Memory budget of the loaded models in megabytes.
+    /// The least recently used models are unloaded to load another model.
+    #[arg(long)]
+    pub memory_budget_mb: Option<u64>,
+
+    /// Also serve any repository on Hugging Face requested by its id, downloaded with the token of the server.
+    /// Only the served model and the presets are served otherwise
+    #[arg(long)]
+    pub allow_hub_models: bool,
+
+    /// Maximum size of a request body in megabytes
+    #[arg(long, default_value = "32")]
+    pub body_limit_mb: usize,
+
+    /// Model to serve
+    #[command(subcommand)]
+    pub model: Option<ModelVersion>,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -43,6 +148,10 @@ pub struct CustomModel {
     #[arg(short, long)]
     pub repo_id: String,
 
+    /// Revision (branch, tag or commit hash) of the repository
+    #[arg(long)]
+    pub revision: Option<String>,
+
     /// Model filename
     #[arg(short, long, default_value = "model.onnx")]
     pub model_file: String,
@@ -54,102 +163,428 @@ pub struct CustomModel {
     /// Tag list filename
     #[arg(short, long, default_value = "selected_tags.csv")]
     pub tags_file: String,
+
+    /// Name of the input of the image tensor [default: the first input]
+    #[arg(long)]
+    pub input_name: Option<String>,
+
+    /// Name of the output of the tag probabilities [default: `output`, or the first output]
+    #[arg(long)]
+    pub output_name: Option<String>,
+
+    /// Layout of the input tensor [default: detected from the shape of the input]
+    #[arg(long)]
+    pub layout: Option<Layout>,
+
+    /// Order of the color channels [default: bgr for nhwc, rgb for nchw]
+    #[arg(long)]
+    pub channel_order: Option<ChannelOrder>,
+
+    /// Range of the pixel values before the normalization [default: byte for nhwc, unit for nchw]
+    #[arg(long)]
+    pub value_range: Option<ValueRange>,
+
+    /// Mean of each channel in RGB order, or one for all channels [default: `mean` of the config for nchw]
+    #[arg(long, value_delimiter = ',')]
+    pub mean: Option<Vec<f32>>,
+
+    /// Standard deviation of each channel in RGB order, or one for all channels [default: `std` of the config for nchw]
+    #[arg(long, value_delimiter = ',')]
+    pub std: Option<Vec<f32>>,
 }
 
-pub trait ModelPreset {
-    fn repo_id(&self) -> String;
-    fn default() -> Self;
+#[derive(Args, Debug, Clone)]
+pub struct Session {
+    /// Fail if the first device does not serve the model, instead of falling back to the next one or the CPU [env: TAGGER_STRICT_DEVICE]
+    #[arg(long)]
+    pub strict_device: bool,
+
+    /// Threads to run each node of the model with [env: TAGGER_INTRA_THREADS]
+    #[arg(long)]
+    pub intra_threads: Option<usize>,
+
+    /// Threads to run the independent nodes in parallel, enabling the parallel execution [env: TAGGER_INTER_THREADS]
+    #[arg(long)]
+    pub inter_threads: Option<usize>,
+
+    /// Graph optimization level [env: TAGGER_GRAPH_OPTIMIZATION] [default: all]
+    #[arg(long)]
+    pub graph_optimization: Option<OptimizationLevel>,
+
+    /// Whether to preallocate the memory by the pattern of the first run [env: TAGGER_MEMORY_PATTERN] [default: true]
+    #[arg(long)]
+    pub memory_pattern: Option<bool>,
+
+    /// Whether to use the memory arena of the CPU [env: TAGGER_MEMORY_ARENA] [default: true]
+    #[arg(long)]
+    pub memory_arena: Option<bool>,
+
+    /// Run on a single thread in sequence to get the same outputs every time [env: TAGGER_DETERMINISTIC]
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Save the optimized model to this path, to load it later with `--graph-optimization disable` [env: TAGGER_OPTIMIZED_MODEL]
+    #[arg(long)]
+    pub save_optimized_model: Option<String>,
 }
 
-#[derive(ValueEnum, Debug, Clone)]
-pub enum V3Model {
-    Vit,
-    SwinV2,
-    Convnext,
-    VitLarge,
-    Eva02Large,
+#[derive(Args, Debug, Clone)]
+pub struct InputOutput {
+    /// Input paths to files or folders
+    #[arg(required_unless_present = "files_from")]
+    pub input: Vec<String>,
+
+    /// Read the paths of the images from a file, one per line, skipping the lines starting with `#`. `-` reads from stdin.
+    #[arg(long)]
+    pub files_from: Option<String>,
+
+    /// Walk into the subfolders of the input folders
+    #[arg(long)]
+    pub recursive: bool,
+
+    /// Only tag the images matching any of the globs, relative to the input folder (e.g. `artist_a/**`)
+    #[arg(long)]
+    pub include: Vec<String>,
+
+    /// Skip the images and folders matching any of the globs, relative to the input folder
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Follow symbolic links in the input folders instead of skipping them
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Include hidden files and folders
+    #[arg(long)]
+    pub include_hidden: bool,
+
+    /// Output path to a file or a folder.
+    /// With `--format caption`, the caption files are written into this folder mirroring the input tree,
+    /// or next to each image if omitted.
+    /// With `--format json`, `jsonl` or `csv`, the records are written into this file, or stdout if omitted.
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Output format
+    #[arg(short, long, default_value = "debug")]
+    pub format: OutputFormat,
+
+    /// Extension of the caption files (e.g. txt, caption, tags)
+    #[arg(long, default_value = "txt")]
+    pub caption_ext: String,
+
+    /// Layout of the table with `--format csv` or `parquet`
+    #[arg(long, default_value = "long")]
+    pub table_layout: TableLayout,
+
+    /// Order of the tag categories in the caption files
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "rating,character,general"
+    )]
+    pub tag_order: Vec<CaptionCategory>,
+
+    /// Threshold for both the general and character tags
+    #[arg(short, long)]
+    pub threshold: Option<f32>,
+
+    /// Threshold for the general tags [default: 0.35]
+    #[arg(long)]
+    pub general_threshold: Option<f32>,
+
+    /// Threshold for the character tags [default: 0.85]
+    #[arg(long)]
+    pub character_threshold: Option<f32>,
+
+    /// Use MCut Thresholding for both the general and character tags
+    #[arg(long)]
+    pub mcut: bool,
+
+    /// Use MCut Thresholding for the general tags
+    #[arg(long)]
+    pub general_mcut: bool,
+
+    /// Use MCut Thresholding for the character tags
+    #[arg(long)]
+    pub character_mcut: bool,
+
+    /// Re-tag the images that already have an up-to-date caption file or JSON Lines record,
+    /// and start the journal over
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// Journal of the completed files to resume an interrupted run exactly where it stopped.
+    /// Only with `--format caption`, or `jsonl` with `--output`.
+    #[arg(long)]
+    pub journal: Option<String>,
+
+    /// Hide the progress bar. It is also hidden when stdout is not a terminal.
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// Write the summary of the run into a JSON file
+    #[arg(long)]
+    pub report: Option<String>,
+
+    /// Number of images to pass to the model at once
+    #[arg(short, long, default_value = "16", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub batch_size: usize,
+
+    /// Memory budget of a batch in megabytes. The batches over the budget are split into smaller ones
+    #[arg(long, value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub batch_memory_mb: Option<u64>,
+
+    /// Memory of an image in a batch in megabytes, including the activations of the model,
+    /// to fit the batches in `--batch-memory-mb` [default: the size of the input tensor]
+    #[arg(long, requires = "batch_memory_mb", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub image_memory_mb: Option<u64>,
+
+    /// Do not probe the largest batch size that fits in the memory of the device on the first batch
+    #[arg(long)]
+    pub no_batch_probe: bool,
+
+    /// Number of images to decode and preprocess in parallel [default: number of CPUs]
+    #[arg(short, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: Option<usize>,
 }
 
-impl ModelPreset for V3Model {
-    fn repo_id(&self) -> String {
-        match self {
-            V3Model::Vit => "SmilingWolf/wd-vit-tagger-v3".to_string(),
-            V3Model::SwinV2 => "SmilingWolf/wd-swinv2-tagger-v3".to_string(),
-            V3Model::Convnext => "SmilingWolf/wd-convnext-tagger-v3".to_string(),
-            V3Model::VitLarge => "SmilingWolf/wd-vit-large-tagger-v3".to_string(),
-            V3Model::Eva02Large => "SmilingWolf/wd-eva02-large-tagger-v3".to_string(),
+impl Cli {
+    /// Model version, including the one to serve
+    pub fn model_version(&self) -> Option<&ModelVersion> {
+        match &self.command {
+            Some(Command::Model(model)) => Some(model),
+            #[cfg(feature = "server")]
+            Some(Command::Serve(serve)) => serve.model.as_ref(),
+            None => None,
         }
     }
 
-    fn default() -> Self {
-        V3Model::SwinV2
+    /// Devices to run the model on
+    pub fn devices(&self) -> Vec<Device> {
+        #[cfg(feature = "tensorrt")]
+        return self
+            .devices
+            .iter()
+            .map(|d| Device::TensorRTDevice(*d))
+            .collect();
+
+        #[cfg(all(feature = "cuda", not(feature = "tensorrt")))]
+        return self
+            .devices
+            .iter()
+            .map(|d| Device::CudaDevice(*d))
+            .collect();
+
+        #[cfg(not(any(feature = "cuda", feature = "tensorrt")))]
+        Device::cpu()
+    }
+
+    /// Whether to serve the HTTP API instead of tagging the inputs
+    pub fn serves(&self) -> bool {
+        #[cfg(feature = "server")]
+        if let Some(Command::Serve(_)) = self.command {
+            return true;
+        }
+        false
+    }
+
+    /// Revision of the model, the one of the custom model takes precedence
+    pub fn revision(&self) -> Option<String> {
+        match self.model_version() {
+            Some(ModelVersion::Custom(custom)) if custom.revision.is_some() => {
+                custom.revision.clone()
+            }
+            _ => self.revision.clone(),
+        }
     }
-}
 
-impl ToString for V3Model {
-    fn to_string(&self) -> String {
-        match self {
-            V3Model::Vit => "vit".to_string(),
-            V3Model::SwinV2 => "swin-v2".to_string(),
-            V3Model::Convnext => "convnext".to_string(),
-            V3Model::VitLarge => "vit-large".to_string(),
-            V3Model::Eva02Large => "eva02-large".to_string(),
+    /// Options to load the model, with the ones of the custom model
+    pub fn load_options(&self) -> anyhow::Result<LoadOptions> {
+        let options = match self.model_version() {
+            Some(ModelVersion::Custom(custom)) => LoadOptions {
+                names: TensorNames {
+                    input: custom.input_name.clone(),
+                    output: custom.output_name.clone(),
+                },
+                preprocess: PreprocessOptions {
+                    layout: custom.layout,
+                    channel_order: custom.channel_order,
+                    value_range: custom.value_range,
+                    mean: custom.mean.clone(),
+                    std: custom.std.clone(),
+                },
+                ..Default::default()
+            },
+            _ => LoadOptions::default(),
+        };
+        Ok(LoadOptions {
+            backend: self.backend,
+            session: self.session.options()?,
+            ..options
+        })
+    }
+
+    /// Options to load the models other than the specified one, e.g. in the server
+    pub fn default_load_options(&self) -> anyhow::Result<LoadOptions> {
+        Ok(LoadOptions {
+            backend: self.backend,
+            session: self.session.options()?,
+            ..Default::default()
+        })
+    }
+
+    /// Options to access the Hugging Face Hub, falling back to the environment variables
+    pub fn hf_options(&self) -> HfOptions {
+        let mut options = HfOptions::from_env();
+        if let Some(endpoint) = &self.hf_endpoint {
+            options = options.with_endpoint(endpoint);
+        }
+        if let Some(token) = &self.hf_token {
+            options = options.with_token(token);
         }
+        if let Some(cache_dir) = &self.hf_cache_dir {
+            options = options.with_cache_dir(cache_dir.into());
+        }
+        if self.offline {
+            options = options.with_offline(true);
+        }
+        if let Some(revision) = self.revision() {
+            options = options.with_revision(&revision);
+        }
+        options
     }
 }
 
-#[derive(ValueEnum, Debug, Clone)]
-pub enum V2Model {
-    Vit,
-    Moat,
-    SwinV2,
-    Convnext,
-    ConvnextV2,
+impl Session {
+    /// Options of the session, falling back to the environment variables
+    pub fn options(&self) -> anyhow::Result<SessionOptions> {
+        let mut options = SessionOptions::from_env()?;
+        if self.strict_device {
+            options = options.with_strict_device(true);
+        }
+        if let Some(threads) = self.intra_threads {
+            options = options.with_intra_threads(threads);
+        }
+        if let Some(threads) = self.inter_threads {
+            options = options.with_inter_threads(threads);
+        }
+        if let Some(level) = self.graph_optimization {
+            options = options.with_optimization_level(level);
+        }
+        if let Some(enable) = self.memory_pattern {
+            options = options.with_memory_pattern(enable);
+        }
+        if let Some(enable) = self.memory_arena {
+            options = options.with_memory_arena(enable);
+        }
+        if self.deterministic {
+            options = options.with_deterministic(true);
+        }
+        if let Some(path) = &self.save_optimized_model {
+            options = options.with_optimized_model_path(path.into());
+        }
+        Ok(options)
+    }
 }
 
-impl ModelPreset for V2Model {
-    fn repo_id(&self) -> String {
-        match self {
-            V2Model::Vit => "SmilingWolf/wd-v1-4-vit-tagger-v2".to_string(),
-            V2Model::Moat => "SmilingWolf/wd-v1-4-moat-tagger-v2".to_string(),
-            V2Model::SwinV2 => "SmilingWolf/wd-v1-4-swinv2-tagger-v2".to_string(),
-            V2Model::Convnext => "SmilingWolf/wd-v1-4-convnext-tagger-v2".to_string(),
-            V2Model::ConvnextV2 => "SmilingWolf/wd-v1-4-convnextv2-tagger-v2".to_string(),
+impl InputOutput {
+    /// Options to walk the input folders
+    pub fn walk_options(&self) -> anyhow::Result<WalkOptions> {
+        Ok(WalkOptions {
+            recursive: self.recursive,
+            follow_symlinks: self.follow_symlinks,
+            include_hidden: self.include_hidden,
+            include: build_globs(&self.include)?,
+            exclude: build_globs(&self.exclude)?,
+        })
+    }
+
+    /// Batcher of the images up to the batch size and under the memory budget
+    pub fn batcher(&self, max_batch_size: usize) -> AdaptiveBatcher {
+        let mut batcher = AdaptiveBatcher::new(max_batch_size).with_probe(!self.no_batch_probe);
+        if let Some(mb) = self.batch_memory_mb {
+            batcher = batcher.with_memory_budget(mb * 1024 * 1024);
+        }
+        if let Some(mb) = self.image_memory_mb {
+            batcher = batcher.with_memory_per_image(mb * 1024 * 1024);
         }
+        batcher
     }
 
-    fn default() -> Self {
-        V2Model::SwinV2
+    /// Number of images to decode and preprocess in parallel
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+    }
+
+    /// Threshold of the general and character tags, and the default of the other categories
+    pub fn threshold(&self) -> Threshold {
+        Threshold::default()
+            .with_category(TagCategory::General, self.general_threshold())
+            .with_category(TagCategory::Character, self.character_threshold())
     }
-}
 
-impl ToString for V2Model {
-    fn to_string(&self) -> String {
-        match self {
-            V2Model::Vit => "vit".to_string(),
-            V2Model::Moat => "moat".to_string(),
-            V2Model::SwinV2 => "swin-v2".to_string(),
-            V2Model::Convnext => "convnext".to_string(),
-            V2Model::ConvnextV2 => "convnext-v2".to_string(),
+    /// Threshold for the general tags
+    pub fn general_threshold(&self) -> Threshold {
+        match self.mcut || self.general_mcut {
+            true => Threshold::MCut,
+            false => Threshold::Fixed(
+                self.general_threshold
+                    .or(self.threshold)
+                    .unwrap_or(DEFAULT_GENERAL_THRESHOLD),
+            ),
         }
     }
-}
 
-#[derive(Args, Debug, Clone)]
-#[group(required = false, multiple = false)]
-pub struct InputOutput {
-    /// Input path to a file or a folder
-    pub input: String,
+    /// Threshold for the character tags
+    pub fn character_threshold(&self) -> Threshold {
+        match self.mcut || self.character_mcut {
+            true => Threshold::MCut,
+            false => Threshold::Fixed(
+                self.character_threshold
+                    .or(self.threshold)
+                    .unwrap_or(DEFAULT_CHARACTER_THRESHOLD),
+            ),
+        }
+    }
+}
 
-    /// Output path to a file or a folder
-    #[arg(short, long)]
-    pub output: Option<String>,
+#[derive(ValueEnum, Debug, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Print the results for debugging
+    Debug,
+    /// Write comma-separated tags to a caption file per image
+    Caption,
+    /// Write a JSON array of the records
+    Json,
+    /// Write a JSON record per line
+    Jsonl,
+    /// Write a table of the results in CSV
+    Csv,
+    /// Write a table of the results in Parquet
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
 
-    /// Threshold for the prediction
-    #[arg(short, long, default_value = "0.35")]
-    pub threshold: f32,
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableLayout {
+    /// A row per (image, tag, category, probability)
+    Long,
+    /// A row per image and a column per tag
+    Wide,
+}
 
-    /// Use MCut Thresholding
-    #[arg(long)]
-    pub mcut: bool,
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptionCategory {
+    /// The most probable rating tag
+    Rating,
+    Character,
+    General,
+    Artist,
+    Copyright,
+    Meta,
 }
@@ -0,0 +1,312 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use ndarray::{Array, Axis, Ix4};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+use wdtagger::{
+    batch::AdaptiveBatcher,
+    error::TaggerError,
+    pipeline::{TaggingPipeline, TaggingResult},
+    processor::{ImagePreprocessor, ImageProcessor},
+};
+
+/// Time spent on an image in each stage
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    pub decode: Duration,
+    pub preprocess: Duration,
+    /// Share of the image in the inference of its batch
+    pub infer: Duration,
+}
+
+/// Image decoded and preprocessed into a tensor
+struct Preprocessed {
+    path: PathBuf,
+    tensor: Result<Array<f32, Ix4>>,
+    timings: Timings,
+}
+
+/// Tagging result, or another output of the model, of an image
+pub struct Tagged<T = TaggingResult> {
+    pub path: PathBuf,
+    pub result: Result<T>,
+    pub timings: Timings,
+}
+
+/// Model run on a preprocessed batch, returning an output per image
+pub type Infer<T> = Arc<dyn Fn(Array<f32, Ix4>) -> Result<Vec<T>, TaggerError> + Send + Sync>;
+
+/// Run the tagging stages in the background and receive the results batch by batch in the order of `files`.
+///
+/// The images are decoded and preprocessed by up to `jobs` tasks on the blocking pool,
+/// grouped into batches of the maximum batch size of `batcher` and tagged by the model,
+/// which splits them further if they do not fit in the memory.
+/// The stages are connected by bounded channels, so only a few batches are in memory at once.
+pub fn spawn(
+    pipe: Arc<TaggingPipeline>,
+    files: Vec<PathBuf>,
+    batcher: Arc<AdaptiveBatcher>,
+    jobs: usize,
+) -> mpsc::Receiver<Vec<Tagged>> {
+    let preprocessor = pipe.preprocessor.clone();
+    let infer: Infer<TaggingResult> = Arc::new(move |tensor| pipe.predict_tensor(tensor));
+    spawn_with(preprocessor, infer, files, batcher, jobs)
+}
+
+/// Run the stages like [`spawn`], with any model that returns an output per image.
+pub fn spawn_with<T: Send + 'static>(
+    preprocessor: ImagePreprocessor,
+    infer: Infer<T>,
+    files: Vec<PathBuf>,
+    batcher: Arc<AdaptiveBatcher>,
+    jobs: usize,
+) -> mpsc::Receiver<Vec<Tagged<T>>> {
+    let (preprocessed_tx, preprocessed_rx) = mpsc::channel(batcher.max_batch_size());
+    let (tagged_tx, tagged_rx) = mpsc::channel(2);
+
+    tokio::spawn(preprocess_stage(preprocessor, files, jobs, preprocessed_tx));
+    tokio::spawn(inference_stage(infer, batcher, preprocessed_rx, tagged_tx));
+
+    tagged_rx
+}
+
+/// Decode and preprocess the images in parallel.
+async fn preprocess_stage(
+    preprocessor: ImagePreprocessor,
+    files: Vec<PathBuf>,
+    jobs: usize,
+    tx: mpsc::Sender<Preprocessed>,
+) {
+    let mut stream = futures::stream::iter(files)
+        .map(|path| {
+            let preprocessor = preprocessor.clone();
+            async move {
+                let task = {
+                    let path = path.clone();
+                    spawn_blocking(move || preprocess(&preprocessor, path))
+                };
+                task.await.unwrap_or_else(|e| Preprocessed {
+                    path,
+                    tensor: Err(e.into()),
+                    timings: Timings::default(),
+                })
+            }
+        })
+        .buffered(jobs.max(1));
+
+    while let Some(preprocessed) = stream.next().await {
+        if tx.send(preprocessed).await.is_err() {
+            // the receiver is gone
+            break;
+        }
+    }
+}
+
+/// Group the preprocessed images into batches and tag them.
+async fn inference_stage<T: Send + 'static>(
+    infer: Infer<T>,
+    batcher: Arc<AdaptiveBatcher>,
+    mut rx: mpsc::Receiver<Preprocessed>,
+    tx: mpsc::Sender<Vec<Tagged<T>>>,
+) {
+    let batch_size = batcher.max_batch_size();
+    let mut batch = Vec::with_capacity(batch_size);
+
+    while let Some(preprocessed) = rx.recv().await {
+        batch.push(preprocessed);
+        if batch.len() >= batch_size {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+            let tagged = tag_batch(infer.clone(), batcher.clone(), full).await;
+            if tx.send(tagged).await.is_err() {
+                // the receiver is gone
+                return;
+            }
+        }
+    }
+
+    // the rest of the images
+    if !batch.is_empty() {
+        let _ = tx.send(tag_batch(infer, batcher, batch).await).await;
+    }
+}
+
+/// Tag the batch on the blocking pool.
+async fn tag_batch<T: Send + 'static>(
+    infer: Infer<T>,
+    batcher: Arc<AdaptiveBatcher>,
+    batch: Vec<Preprocessed>,
+) -> Vec<Tagged<T>> {
+    let items = batch
+        .iter()
+        .map(|item| (item.path.clone(), item.timings))
+        .collect::<Vec<_>>();
+    match spawn_blocking(move || predict(&infer, &batcher, batch)).await {
+        Ok(tagged) => tagged,
+        Err(e) => items
+            .into_iter()
+            .map(|(path, timings)| Tagged {
+                path,
+                result: Err(anyhow::anyhow!("The inference task failed: {}", e)),
+                timings,
+            })
+            .collect(),
+    }
+}
+
+/// Decode and preprocess an image.
+fn preprocess(preprocessor: &ImagePreprocessor, path: PathBuf) -> Preprocessed {
+    let mut timings = Timings::default();
+
+    let start = Instant::now();
+    let image = image::open(&path).context("Failed to load the image");
+    timings.decode = start.elapsed();
+
+    let start = Instant::now();
+    let tensor = image.and_then(|image| Ok(preprocessor.process(&image)?));
+    timings.preprocess = start.elapsed();
+
+    Preprocessed {
+        path,
+        tensor,
+        timings,
+    }
+}
+
+/// Tag a batch of images, keeping the errors of the images that failed to preprocess.
+fn predict<T>(
+    infer: &Infer<T>,
+    batcher: &AdaptiveBatcher,
+    batch: Vec<Preprocessed>,
+) -> Vec<Tagged<T>> {
+    let mut tagged = Vec::with_capacity(batch.len());
+    // index in the batch and tensor of each preprocessed image
+    let mut indices = Vec::with_capacity(batch.len());
+    let mut tensors = Vec::with_capacity(batch.len());
+
+    for (idx, item) in batch.into_iter().enumerate() {
+        let result = match item.tensor {
+            Ok(tensor) => {
+                indices.push(idx);
+                tensors.push(tensor);
+                Err(anyhow::anyhow!("Not tagged"))
+            }
+            Err(e) => Err(e),
+        };
+        tagged.push(Tagged {
+            path: item.path,
+            result,
+            timings: item.timings,
+        });
+    }
+
+    if tensors.is_empty() {
+        return tagged;
+    }
+
+    let start = Instant::now();
+    let count = tensors.len() as u32;
+    let views = tensors
+        .iter()
+        .map(|tensor| tensor.view())
+        .collect::<Vec<_>>();
+    let results = match ndarray::concatenate(Axis(0), &views) {
+        // a bad image fails only itself, and the batches that do not fit in the memory are split
+        Ok(tensor) => batcher
+            .run(tensor, |tensor| infer(tensor))
+            .into_iter()
+            .map(|result| result.map_err(anyhow::Error::from))
+            .collect::<Vec<_>>(),
+        Err(e) => (0..count)
+            .map(|_| Err(anyhow::anyhow!("Failed to make the batch: {}", e)))
+            .collect(),
+    };
+
+    let infer = start.elapsed() / count;
+    for (idx, result) in indices.into_iter().zip(results) {
+        tagged[idx].result = result;
+        tagged[idx].timings.infer = infer;
+    }
+    tagged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::TempDir;
+    use std::sync::Mutex;
+
+    /// Create the images filled with increasing shades of gray, with a broken one at `broken`.
+    fn create_images(temp: &TempDir, count: usize, broken: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|idx| {
+                let path = temp.path().join(format!("{:02}.png", idx));
+                match idx == broken {
+                    true => std::fs::write(&path, b"not an image").unwrap(),
+                    false => image::GrayImage::from_pixel(4, 4, image::Luma([idx as u8 * 10]))
+                        .save(&path)
+                        .unwrap(),
+                }
+                path
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_spawn_with() {
+        let temp = TempDir::new("spawn-with");
+        let files = create_images(&temp, 8, 3);
+        let max_batch_size = 3;
+
+        // the mean of each image, and the size of the batch it was run in
+        let sizes = Arc::new(Mutex::new(vec![]));
+        let infer: Infer<(f32, usize)> = {
+            let sizes = sizes.clone();
+            Arc::new(move |tensor| {
+                let size = tensor.len_of(Axis(0));
+                sizes.lock().unwrap().push(size);
+                Ok(tensor
+                    .outer_iter()
+                    .map(|image| (image.mean().unwrap(), size))
+                    .collect())
+            })
+        };
+        let batcher = Arc::new(AdaptiveBatcher::new(max_batch_size));
+        let mut batches = spawn_with(
+            ImagePreprocessor::new(3, 8, 8),
+            infer,
+            files.clone(),
+            batcher,
+            4,
+        );
+
+        let mut tagged = vec![];
+        while let Some(batch) = batches.recv().await {
+            assert!(!batch.is_empty() && batch.len() <= max_batch_size);
+            tagged.extend(batch);
+        }
+
+        assert_eq!(
+            tagged
+                .iter()
+                .map(|tagged| tagged.path.clone())
+                .collect::<Vec<_>>(),
+            files
+        );
+        // only the broken image fails
+        assert!(tagged[3].result.is_err());
+        let means = tagged
+            .iter()
+            .filter_map(|tagged| tagged.result.as_ref().ok())
+            .map(|(mean, _)| *mean)
+            .collect::<Vec<_>>();
+        assert_eq!(means.len(), 7);
+        assert!(means.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let sizes = sizes.lock().unwrap();
+        assert!(sizes.iter().all(|size| *size <= max_batch_size));
+        assert_eq!(sizes.iter().sum::<usize>(), 7);
+    }
+}
@@ -1,9 +1,11 @@
 use anyhow::Result;
-use futures::stream::{self, StreamExt};
-use std::path::PathBuf;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs;
-use tokio::fs::{File, ReadDir};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 /// Supported image extensions.
 pub const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
@@ -15,57 +17,396 @@ pub async fn is_file(path: &str) -> Result<bool> {
 }
 
 /// Check if the path is an image file.
-pub fn is_image(path: &str) -> Result<bool> {
-    match PathBuf::from(path).extension() {
+pub fn is_image<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().extension() {
         Some(ext) => {
             let ext = ext.to_string_lossy().to_lowercase();
-            Ok(IMAGE_EXTENSIONS.contains(&ext.as_str()))
+            IMAGE_EXTENSIONS.contains(&ext.as_str())
         }
-        None => Ok(false),
+        None => false,
     }
 }
 
+/// Check if the file or folder is hidden.
+pub fn is_hidden<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Build a set of glob patterns, `None` if there is no pattern.
+pub fn build_globs(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Options to walk the input folders.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Walk into the subfolders
+    pub recursive: bool,
+    /// Follow symbolic links, or skip them
+    pub follow_symlinks: bool,
+    /// Include hidden files and folders
+    pub include_hidden: bool,
+    /// Only include the files matching any of the globs, relative to the input folder
+    pub include: Option<GlobSet>,
+    /// Exclude the files and folders matching any of the globs, relative to the input folder
+    pub exclude: Option<GlobSet>,
+}
+
 /// Get image files from a directory.
-pub async fn get_image_files(dir: &str) -> Result<Vec<PathBuf>> {
-    let mut entries = fs::read_dir(dir).await?;
-    let mut tasks = vec![];
-
-    while let Some(entry) = entries.next_entry().await? {
-        let path = entry.path();
-        let task = tokio::spawn(async move {
-            if is_image(path.to_str().unwrap()).unwrap() {
-                Some(path)
-            } else {
-                None
+pub async fn get_image_files(dir: &Path, options: &WalkOptions) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    // canonical paths of the visited folders to avoid symlink loops
+    let mut visited = HashSet::new();
+
+    while let Some(current) = dirs.pop() {
+        let Some(mut entries) = open_dir(&current, dir, &mut visited).await? else {
+            continue;
+        };
+
+        loop {
+            let entry = match entries.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Warning: Failed to read {}: {}", current.display(), e);
+                    break;
+                }
+            };
+            let path = entry.path();
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+
+            if !options.include_hidden && is_hidden(&path) {
+                continue;
+            }
+            if let Some(exclude) = &options.exclude {
+                if exclude.is_match(relative) {
+                    continue;
+                }
             }
-        });
 
-        tasks.push(task);
-    }
+            // the entries removed while walking are skipped
+            let Ok(file_type) = entry.file_type().await else {
+                continue;
+            };
+            let metadata = match file_type.is_symlink() {
+                true if !options.follow_symlinks => continue,
+                // broken link
+                true => fs::metadata(&path).await,
+                false => entry.metadata().await,
+            };
+            let Ok(metadata) = metadata else {
+                continue;
+            };
 
-    let files = stream::iter(tasks)
-        .buffer_unordered(16)
-        .filter_map(|result| async move {
-            match result {
-                Ok(Some(path)) => Some(path),
-                _ => None,
+            if metadata.is_dir() {
+                if options.recursive {
+                    dirs.push(path);
+                }
+            } else if metadata.is_file()
+                && is_image(&path)
+                && options
+                    .include
+                    .as_ref()
+                    .is_none_or(|include| include.is_match(relative))
+            {
+                files.push(path);
             }
-        })
-        .collect()
-        .await;
+        }
+    }
+    files.sort();
 
     Ok(files)
 }
 
-/// Write a text to a file.
-pub async fn write_text_to_file(text: &str, path: &str) -> Result<()> {
+/// Open the folder to walk, `None` if it was visited already.
+/// A subfolder that cannot be read is reported and skipped, but the input folder must be readable.
+async fn open_dir(
+    path: &Path,
+    root: &Path,
+    visited: &mut HashSet<PathBuf>,
+) -> Result<Option<fs::ReadDir>> {
+    let result: std::io::Result<_> = async {
+        if !visited.insert(fs::canonicalize(path).await?) {
+            return Ok(None);
+        }
+        Ok(Some(fs::read_dir(path).await?))
+    }
+    .await;
+
+    match result {
+        Ok(entries) => Ok(entries),
+        Err(e) if path != root => {
+            eprintln!("Warning: Skipping {}: {}", path.display(), e);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read a list of files, one path per line. `-` reads from stdin.
+/// Blank lines and lines starting with `#` are skipped.
+pub async fn read_file_list(path: &str) -> Result<Vec<PathBuf>> {
+    read_file_list_from(path, tokio::io::stdin()).await
+}
+
+/// Read a list of files, where `-` reads from `stdin`.
+async fn read_file_list_from<R: AsyncRead + Unpin>(
+    path: &str,
+    mut stdin: R,
+) -> Result<Vec<PathBuf>> {
+    let mut text = String::new();
+    match path {
+        "-" => {
+            stdin.read_to_string(&mut text).await?;
+        }
+        _ => {
+            File::open(path).await?.read_to_string(&mut text).await?;
+        }
+    }
+
+    Ok(text
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Write the bytes to a file.
+pub async fn write_bytes_to_file<P: AsRef<Path>>(bytes: &[u8], path: P) -> Result<()> {
     let mut file = File::create(path).await?;
-    file.write_all(text.as_bytes()).await?;
+    file.write_all(bytes).await?;
+    // tokio writes in the background, so the file is complete only after the flush
+    file.flush().await?;
     Ok(())
 }
 
-/// Create a directory.
-pub async fn create_dir(path: &str) -> Result<()> {
-    fs::create_dir(path).await?;
+/// Create a directory and its parents if missing.
+pub async fn create_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+    fs::create_dir_all(path).await?;
     Ok(())
 }
+
+/// Check if the file exists and was modified at the same time as or after the source file.
+pub async fn is_up_to_date<P: AsRef<Path>, Q: AsRef<Path>>(path: P, source: Q) -> bool {
+    let (Ok(metadata), Ok(source_metadata)) =
+        (fs::metadata(path).await, fs::metadata(source).await)
+    else {
+        return false;
+    };
+    match (metadata.modified(), source_metadata.modified()) {
+        (Ok(modified), Ok(source_modified)) => modified >= source_modified,
+        _ => false,
+    }
+}
+
+/// Get the time in milliseconds since the Unix epoch.
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Check if the file exists and was not modified after the time in milliseconds since the Unix epoch.
+pub async fn is_unmodified_since<P: AsRef<Path>>(path: P, time: u64) -> bool {
+    match fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+    {
+        Ok(modified) => unix_millis(modified) <= time,
+        Err(_) => false,
+    }
+}
+
+/// Empty folder for the files of a test, unique to the test and the process,
+/// and removed when dropped
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("tagger-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Create the files, and their folders, under the root.
+    fn create_files(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+    }
+
+    /// Walk the folder and get the paths of the images relative to it.
+    async fn walk(root: &Path, options: &WalkOptions) -> Vec<String> {
+        get_image_files(root, options)
+            .await
+            .unwrap()
+            .iter()
+            .map(|path| {
+                path.strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_get_image_files_recursive() {
+        let temp = TempDir::new("walk-recursive");
+        create_files(
+            temp.path(),
+            &["a.png", "notes.txt", "x/b.JPG", "x/y/c.webp"],
+        );
+
+        let flat = WalkOptions::default();
+        assert_eq!(walk(temp.path(), &flat).await, vec!["a.png"]);
+
+        let recursive = WalkOptions {
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            walk(temp.path(), &recursive).await,
+            vec!["a.png", "x/b.JPG", "x/y/c.webp"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_image_files_globs() {
+        let temp = TempDir::new("walk-globs");
+        create_files(
+            temp.path(),
+            &[
+                "a.png",
+                "artist_a/b.png",
+                "artist_a/wip/c.png",
+                "artist_b/d.png",
+            ],
+        );
+
+        let options = WalkOptions {
+            recursive: true,
+            include: build_globs(&["artist_a/**".to_string()]).unwrap(),
+            exclude: build_globs(&["**/wip".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(walk(temp.path(), &options).await, vec!["artist_a/b.png"]);
+
+        let options = WalkOptions {
+            recursive: true,
+            exclude: build_globs(&["artist_*/**".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(walk(temp.path(), &options).await, vec!["a.png"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_image_files_hidden() {
+        let temp = TempDir::new("walk-hidden");
+        create_files(temp.path(), &["a.png", ".b.png", ".cache/c.png"]);
+
+        let options = WalkOptions {
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(walk(temp.path(), &options).await, vec!["a.png"]);
+
+        let options = WalkOptions {
+            recursive: true,
+            include_hidden: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            walk(temp.path(), &options).await,
+            vec![".b.png", ".cache/c.png", "a.png"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_get_image_files_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let temp = TempDir::new("walk-symlinks");
+        let root = temp.path().join("root");
+        create_files(temp.path(), &["root/a.png", "root/x/b.png", "other/c.png"]);
+        symlink(temp.path().join("other"), root.join("linked")).unwrap();
+        symlink(root.join("a.png"), root.join("link.png")).unwrap();
+        symlink(temp.path().join("missing.png"), root.join("broken.png")).unwrap();
+        // a loop back to the input folder
+        symlink(&root, root.join("x/loop")).unwrap();
+
+        let options = WalkOptions {
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(walk(&root, &options).await, vec!["a.png", "x/b.png"]);
+
+        // the loop is walked once, so only the links to files add more images
+        let options = WalkOptions {
+            recursive: true,
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            walk(&root, &options).await,
+            vec!["a.png", "link.png", "linked/c.png", "x/b.png"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_file_list() {
+        let temp = TempDir::new("file-list");
+        let list = temp.path().join("files.txt");
+        std::fs::write(&list, "a.png\n\n  # a comment\n  x/b.png  \n#c.png\n").unwrap();
+
+        let files = read_file_list(&list.to_string_lossy()).await.unwrap();
+        assert_eq!(
+            files,
+            vec![PathBuf::from("a.png"), PathBuf::from("x/b.png")]
+        );
+
+        let stdin = "c.png\r\n\r\nd.png".as_bytes();
+        let files = read_file_list_from("-", stdin).await.unwrap();
+        assert_eq!(files, vec![PathBuf::from("c.png"), PathBuf::from("d.png")]);
+
+        assert!(
+            read_file_list(&temp.path().join("missing.txt").to_string_lossy())
+                .await
+                .is_err()
+        );
+    }
+}
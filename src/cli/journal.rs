@@ -0,0 +1,165 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::file;
+
+/// Journal of the completed files to resume an interrupted run.
+/// Each line is the time the file was tagged, in milliseconds since the Unix epoch, and its path.
+pub struct Journal {
+    file: File,
+    completed: HashMap<PathBuf, u64>,
+}
+
+impl Journal {
+    /// Open the journal, or start a new one if `overwrite` is set.
+    pub fn open<P: AsRef<Path>>(path: P, overwrite: bool) -> Result<Self> {
+        let path = path.as_ref();
+        let text = match path.is_file() && !overwrite {
+            true => std::fs::read_to_string(path)?,
+            false => String::new(),
+        };
+
+        let mut lines = text.lines().collect::<Vec<_>>();
+        let interrupted = !text.is_empty() && !text.ends_with('\n');
+        if interrupted {
+            // the last line was cut off while being written
+            lines.pop();
+        }
+        let completed = lines
+            .into_iter()
+            .filter_map(|line| {
+                let (tagged_at, path) = line.split_once(' ')?;
+                Some((PathBuf::from(path), tagged_at.parse().ok()?))
+            })
+            .collect();
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(!overwrite)
+            .write(true)
+            .truncate(overwrite)
+            .open(path)?;
+        if interrupted {
+            writeln!(file)?;
+        }
+
+        Ok(Self { file, completed })
+    }
+
+    /// Check if the file was completed by a previous run and not modified since.
+    pub async fn is_done(&self, path: &Path) -> bool {
+        match self.completed.get(path) {
+            Some(tagged_at) => file::is_unmodified_since(path, *tagged_at).await,
+            None => false,
+        }
+    }
+
+    /// Record the files as completed.
+    pub fn record(&mut self, paths: &[&PathBuf]) -> Result<()> {
+        let tagged_at = file::unix_millis(SystemTime::now());
+        for path in paths {
+            writeln!(self.file, "{} {}", tagged_at, path.display())?;
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::TempDir;
+    use std::time::Duration;
+
+    /// Create the image with the modification time relative to now.
+    fn create_image(path: &Path, offset_secs: i64) {
+        let now = SystemTime::now();
+        let time = match offset_secs >= 0 {
+            true => now + Duration::from_secs(offset_secs as u64),
+            false => now - Duration::from_secs(offset_secs.unsigned_abs()),
+        };
+        File::create(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let temp = TempDir::new("journal-record");
+        let (a, b) = (temp.path().join("a.png"), temp.path().join("b png"));
+        create_image(&a, -3600);
+        create_image(&b, -3600);
+        let path = temp.path().join("tagged.log");
+
+        let mut journal = Journal::open(&path, false).unwrap();
+        assert!(!journal.is_done(&a).await);
+        journal.record(&[&a, &b]).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path, false).unwrap();
+        assert!(journal.is_done(&a).await);
+        assert!(journal.is_done(&b).await);
+        assert!(!journal.is_done(&temp.path().join("c.png")).await);
+    }
+
+    #[tokio::test]
+    async fn test_edited_after_record() {
+        let temp = TempDir::new("journal-edited");
+        let a = temp.path().join("a.png");
+        create_image(&a, -3600);
+        let path = temp.path().join("tagged.log");
+
+        Journal::open(&path, false).unwrap().record(&[&a]).unwrap();
+        create_image(&a, 60);
+
+        let journal = Journal::open(&path, false).unwrap();
+        assert!(!journal.is_done(&a).await);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_last_line() {
+        let temp = TempDir::new("journal-truncated");
+        let (a, b) = (temp.path().join("a.png"), temp.path().join("b.png"));
+        create_image(&a, -3600);
+        create_image(&b, -3600);
+        let path = temp.path().join("tagged.log");
+
+        Journal::open(&path, false).unwrap().record(&[&a]).unwrap();
+        // a line cut off by an interruption
+        let line = format!("{} {}", file::unix_millis(SystemTime::now()), b.display());
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&line.as_bytes()[..line.len() - 3])
+            .unwrap();
+
+        let mut journal = Journal::open(&path, false).unwrap();
+        assert!(journal.is_done(&a).await);
+        assert!(!journal.is_done(&b).await);
+
+        // the next record starts on a new line
+        journal.record(&[&b]).unwrap();
+        drop(journal);
+        let journal = Journal::open(&path, false).unwrap();
+        assert!(journal.is_done(&a).await);
+        assert!(journal.is_done(&b).await);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_overwrite() {
+        let temp = TempDir::new("journal-overwrite");
+        let a = temp.path().join("a.png");
+        create_image(&a, -3600);
+        let path = temp.path().join("tagged.log");
+
+        Journal::open(&path, false).unwrap().record(&[&a]).unwrap();
+
+        let journal = Journal::open(&path, true).unwrap();
+        assert!(!journal.is_done(&a).await);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    }
+}
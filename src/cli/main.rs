@@ -1,108 +1,420 @@
 mod args;
+mod batch;
 mod file;
+mod journal;
+mod output;
+mod report;
+#[cfg(feature = "server")]
+mod server;
+mod table;
 
 use anyhow::Result;
-use args::{Cli, ModelPreset, ModelVersion, V3Model};
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use args::{Cli, InputOutput, ModelVersion, OutputFormat};
+use batch::{Infer, Tagged};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use indicatif::ProgressBar;
+use journal::Journal;
+use output::{CaptionWriter, JsonWriter, ModelInfo, Output};
+use report::Summary;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use table::TableWriter;
 use wdtagger::{
-    config::ModelConfig,
-    file::{ConfigFile, HfFile, TagCSVFile, TaggerModelFile},
+    embedding::{EmbeddingFormat, EmbeddingPipeline},
+    file::{resolved_revision, ConfigFile, HfFile, TagCSVFile, TaggerModelFile},
     pipeline::TaggingPipeline,
-    processor::ImagePreprocessor,
-    tagger::{Device, TaggerModel},
-    tags::LabelTags,
+    preset::{ModelPreset, V3Model},
+    registry::{ModelRegistry, ModelSource},
 };
 
-/// Get the target device type.
-fn target_device_type() -> String {
-    if cfg!(feature = "tensorrt") {
-        "TensorRT".to_string()
-    } else if cfg!(feature = "cuda") {
-        "CUDA".to_string()
-    } else {
-        "CPU".to_string()
+/// Collect the image files from the inputs and the file list, without duplicates.
+/// Returns the root folders of the inputs and the image files.
+async fn collect_inputs(io: &InputOutput) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let walk_options = io.walk_options()?;
+    let mut roots = vec![];
+    let mut files = vec![];
+
+    for input in &io.input {
+        let input_path = PathBuf::from(input);
+        // if input is single file
+        match file::is_file(input).await? {
+            true => {
+                roots.push(
+                    input_path
+                        .parent()
+                        .map(|p| p.to_path_buf())
+                        .unwrap_or_default(),
+                );
+                files.push(input_path);
+            }
+            false => {
+                files.extend(file::get_image_files(&input_path, &walk_options).await?);
+                roots.push(input_path);
+            }
+        }
+    }
+
+    if let Some(files_from) = &io.files_from {
+        files.extend(file::read_file_list(files_from).await?);
     }
+
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(file.clone()));
+
+    Ok((roots, files))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let target_device = target_device_type();
-    println!("Target device: <{}>", target_device);
+/// Get the files that are not tagged yet by a previous run.
+async fn pending_files(
+    files: Vec<PathBuf>,
+    output: &Output,
+    journal: Option<&Journal>,
+) -> Vec<PathBuf> {
+    let mut pending = Vec::with_capacity(files.len());
+    for file in files {
+        let done = match journal {
+            Some(journal) if journal.is_done(&file).await => true,
+            _ => output.is_done(&file).await,
+        };
+        if !done {
+            pending.push(file);
+        }
+    }
+    pending
+}
 
-    let cli = Cli::parse();
+/// Tag the image files batch by batch and write the result of each file.
+/// A file that fails to load, predict or write is reported and skipped.
+async fn tag_files(
+    pipe: Arc<TaggingPipeline>,
+    files: Vec<PathBuf>,
+    io: &InputOutput,
+    output: &mut Output,
+    mut journal: Option<&mut Journal>,
+    summary: &mut Summary,
+    progress: &ProgressBar,
+) -> Result<()> {
+    let batcher = Arc::new(io.batcher(io.batch_size));
+    let mut batches = batch::spawn(pipe, files, batcher, io.jobs());
+    while let Some(batch) = batches.recv().await {
+        let mut completed = Vec::with_capacity(batch.len());
+        for Tagged {
+            path,
+            result,
+            timings,
+        } in &batch
+        {
+            let start = Instant::now();
+            let written = match result {
+                Ok(result) => output.write(path, result).await.map(|_| result),
+                Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+            };
+            match written {
+                Ok(result) => {
+                    summary.succeeded(timings, start.elapsed());
+                    summary.count_tags(result);
+                    completed.push(path);
+                }
+                Err(e) => {
+                    progress.suspend(|| eprintln!("Failed to tag {}: {:#}", path.display(), e));
+                    summary.failed();
+                }
+            }
+            progress.inc(1);
+        }
 
-    let device = Device::cpu();
+        // record the batch only after its results are on disk
+        output.flush()?;
+        if let Some(journal) = journal.as_deref_mut() {
+            journal.record(&completed)?;
+        }
+    }
 
-    #[cfg(feature = "cuda")]
-    let device: Vec<Device> = cli.devices.iter().map(|d| Device::CudaDevice(*d)).collect();
+    Ok(())
+}
+
+/// Extract the embeddings of the images and write them into a file per image.
+async fn embed_files(
+    cli: &Cli,
+    model_path: PathBuf,
+    config_path: PathBuf,
+    format: EmbeddingFormat,
+) -> Result<()> {
+    if cli.io.journal.is_some() {
+        anyhow::bail!("--journal is not supported with --embeddings");
+    }
+
+    let model_path = match &cli.embedding_model {
+        Some(path) => PathBuf::from(path),
+        None => model_path,
+    };
+    let pipe = Arc::new(EmbeddingPipeline::load_with_options(
+        model_path,
+        config_path,
+        cli.embedding_output.as_deref(),
+        cli.devices(),
+        &cli.load_options()?,
+    )?);
+    eprintln!("Device: <{}>", pipe.model.device());
+    eprintln!(
+        "Extracting {} features from the output <{}>",
+        pipe.num_features(),
+        pipe.output()
+    );
 
-    #[cfg(feature = "tensorrt")]
-    let device: Vec<Device> = cli
-        .devices
-        .iter()
-        .map(|d| Device::TensorRTDevice(*d))
-        .collect();
+    let (roots, files) = collect_inputs(&cli.io).await?;
+    let writer = CaptionWriter::new(
+        roots,
+        cli.io.output.as_ref().map(PathBuf::from),
+        format.extension(),
+        vec![],
+    )
+    .with_images(&files);
+    let total = files.len();
+    let files = match cli.io.overwrite {
+        true => files,
+        false => {
+            let mut pending = Vec::with_capacity(files.len());
+            for file in files {
+                if writer.has_conflict(&file)
+                    || !file::is_up_to_date(writer.caption_path(&file), &file).await
+                {
+                    pending.push(file);
+                }
+            }
+            pending
+        }
+    };
+
+    let mut summary = Summary::new(total - files.len());
+    let progress = report::progress_bar(files.len(), !cli.io.no_progress);
+    let preprocessor = pipe.preprocessor.clone();
+    let infer: Infer<Vec<f32>> = Arc::new(move |tensor| pipe.predict_tensor(tensor));
+    let batcher = Arc::new(cli.io.batcher(cli.io.batch_size));
+    let mut batches = batch::spawn_with(preprocessor, infer, files, batcher, cli.io.jobs());
+    while let Some(batch) = batches.recv().await {
+        for Tagged {
+            path,
+            result,
+            timings,
+        } in batch
+        {
+            let start = Instant::now();
+            let written = match result {
+                Ok(embedding) => {
+                    let bytes = format.encode(&embedding, &[embedding.len()]);
+                    writer.write_bytes(&path, &bytes).await
+                }
+                Err(e) => Err(e),
+            };
+            match written {
+                Ok(_) => summary.succeeded(&timings, start.elapsed()),
+                Err(e) => {
+                    progress.suspend(|| eprintln!("Failed to embed {}: {:#}", path.display(), e));
+                    summary.failed();
+                }
+            }
+            progress.inc(1);
+        }
+    }
+    progress.finish_and_clear();
+
+    let report = summary.report();
+    report.print();
+    if let Some(path) = &cli.io.report {
+        report.save(path)?;
+    }
+
+    Ok(())
+}
 
-    let repo_id = match &cli.model {
+/// Download or locate the model files.
+/// Returns the name of the model in the registry, its files and its information.
+fn model_files(cli: &Cli) -> Result<(String, ModelSource, ModelInfo)> {
+    let repo_id = match cli.model_version() {
         Some(ModelVersion::V2 { model }) => model.repo_id(),
         Some(ModelVersion::V3 { model }) => model.repo_id(),
         Some(ModelVersion::Custom(custom)) => custom.repo_id.clone(),
         None => V3Model::default().repo_id(),
     };
-    let model_file = match &cli.model {
+    let model_file = match cli.model_version() {
         Some(ModelVersion::Custom(custom)) => custom.model_file.clone(),
         _ => "model.onnx".to_string(),
     };
-    let config_file = match &cli.model {
+    let config_file = match cli.model_version() {
         Some(ModelVersion::Custom(custom)) => custom.config_file.clone(),
         _ => "config.json".to_string(),
     };
-    let tag_csv_file = match &cli.model {
+    let tag_csv_file = match cli.model_version() {
         Some(ModelVersion::Custom(custom)) => custom.tags_file.clone(),
         _ => "selected_tags.csv".to_string(),
     };
 
-    // define files
-    let model_file = TaggerModelFile::custom(&repo_id, None, &model_file);
-    let config_file = ConfigFile::custom(&repo_id, None, &config_file);
-    let tag_csv_file = TagCSVFile::custom(&repo_id, None, &tag_csv_file);
+    let (model_file_path, config_file_path, tag_csv_file_path) = match &cli.model_dir {
+        Some(model_dir) => {
+            let model_dir = PathBuf::from(model_dir);
+            let paths = (
+                model_dir.join(model_file),
+                model_dir.join(config_file),
+                model_dir.join(tag_csv_file),
+            );
+            for path in [&paths.0, &paths.1, &paths.2] {
+                if !path.is_file() {
+                    anyhow::bail!("{} is not found", path.display());
+                }
+            }
+            paths
+        }
+        None => {
+            // define files
+            let revision = cli.revision();
+            let model_file = TaggerModelFile::custom(&repo_id, revision.clone(), &model_file);
+            let config_file = ConfigFile::custom(&repo_id, revision.clone(), &config_file);
+            let tag_csv_file = TagCSVFile::custom(&repo_id, revision, &tag_csv_file);
+
+            // pre-download files, or use cached files if offline
+            let options = cli.hf_options();
+            (
+                model_file.get_with_options(&options)?,
+                config_file.get_with_options(&options)?,
+                tag_csv_file.get_with_options(&options)?,
+            )
+        }
+    };
+
+    let model_info = ModelInfo {
+        repo_id: cli.model_dir.clone().unwrap_or(repo_id),
+        revision: cli.revision(),
+        commit: resolved_revision(&model_file_path),
+    };
+    let name = match cli.model_version() {
+        Some(ModelVersion::V2 { model }) => model.name(),
+        Some(ModelVersion::V3 { model }) => model.name(),
+        Some(ModelVersion::Custom(_)) => model_info.repo_id.clone(),
+        None => V3Model::default().name(),
+    };
+    let source = ModelSource::Files {
+        model: model_file_path,
+        config: config_file_path,
+        tags: tag_csv_file_path,
+    };
 
-    // pre-download files
-    let model_file_path = model_file.get()?;
-    let config_file_path = config_file.get()?;
-    let tag_csv_file_path = tag_csv_file.get()?;
+    Ok((name, source, model_info))
+}
 
-    // - maybe change the thread later
+#[tokio::main]
+async fn main() -> Result<()> {
+    // logs of ONNX Runtime by RUST_LOG, on stderr to keep stdout for the outputs
+    if let Err(e) = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .try_init()
+    {
+        eprintln!("Warning: Failed to initialize the logger: {}", e);
+    }
 
-    // load model
-    TaggerModel::use_devices(device)?; // do once
-    let model = TaggerModel::load(&model_file_path)?;
-    let config = ModelConfig::load(&config_file_path)?;
-    let preprocessor = ImagePreprocessor::from_config(&config)?;
-    let label_tags = LabelTags::load(&tag_csv_file_path)?;
+    let cli = Cli::parse();
+    if !cli.serves() && cli.io.input.is_empty() && cli.io.files_from.is_none() {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the input paths or --files-from is required",
+            )
+            .exit();
+    }
 
-    // load pipe
-    let threshold = &cli.io.threshold;
-    let pipe = TaggingPipeline::new(model, preprocessor, label_tags, threshold);
+    let (name, source, model_info) = model_files(&cli)?;
+    let registry = ModelRegistry::new(cli.devices())
+        .with_threshold(cli.io.threshold())
+        .with_load_options(cli.default_load_options()?);
+    registry.register_with_options(&name, source.clone(), cli.load_options()?);
+
+    #[cfg(feature = "server")]
+    if let Some(args::Command::Serve(serve)) = &cli.command {
+        return server::serve(registry, &name, cli.hf_options(), serve, &cli.io).await;
+    }
+
+    if let (Some(format), ModelSource::Files { model, config, .. }) = (cli.embeddings, source) {
+        return embed_files(&cli, model, config, format).await;
+    }
+
+    let pipe = registry.get(&name)?;
+    eprintln!("Device: <{}>", pipe.model.device());
 
     // I/O
-    let input = &cli.io.input;
-    let output = &cli.io.output;
-    let mcut = &cli.io.mcut;
-
-    // if input is single file
-    match file::is_file(&input).await? {
-        true => {
-            let img = image::open(&input)?;
-            let result = pipe.predict(img)?;
-            dbg!(result);
-        }
-        false => {
-            unimplemented!("Folder input is not implemented yet");
+    let (roots, files) = collect_inputs(&cli.io).await?;
+
+    let output_path = cli.io.output.as_ref().map(PathBuf::from);
+
+    let mut output = match cli.io.format {
+        OutputFormat::Debug => Output::Debug,
+        OutputFormat::Caption => Output::Caption(
+            CaptionWriter::new(
+                roots,
+                output_path,
+                &cli.io.caption_ext,
+                cli.io.tag_order.clone(),
+            )
+            .with_images(&files),
+        ),
+        OutputFormat::Json | OutputFormat::Jsonl => Output::Json(JsonWriter::new(
+            output_path.as_deref(),
+            model_info,
+            pipe.threshold(),
+            cli.io.format == OutputFormat::Jsonl,
+            !cli.io.overwrite,
+        )?),
+        OutputFormat::Csv => Output::Table(Box::new(TableWriter::csv(
+            output_path.as_deref(),
+            cli.io.table_layout,
+            &pipe.tags,
+        )?)),
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => Output::Table(Box::new(TableWriter::parquet(
+            output_path.as_deref(),
+            cli.io.table_layout,
+            &pipe.tags,
+        )?)),
+    };
+
+    // resume
+    let mut journal = match &cli.io.journal {
+        Some(_) if !output.is_resumable() => {
+            anyhow::bail!(
+                "--journal is only supported with --format caption, or jsonl with --output"
+            )
         }
-    }
+        Some(path) => Some(Journal::open(path, cli.io.overwrite)?),
+        None => None,
+    };
+    let total = files.len();
+    let files = match cli.io.overwrite {
+        true => files,
+        false => pending_files(files, &output, journal.as_ref()).await,
+    };
+
+    let mut summary = Summary::new(total - files.len());
+    let progress = report::progress_bar(files.len(), !cli.io.no_progress);
+    tag_files(
+        pipe,
+        files,
+        &cli.io,
+        &mut output,
+        journal.as_mut(),
+        &mut summary,
+        &progress,
+    )
+    .await?;
+    output.finish()?;
+    progress.finish_and_clear();
 
-    dbg!(&cli);
+    let report = summary.report();
+    report.print();
+    if let Some(path) = &cli.io.report {
+        report.save(path)?;
+    }
 
     Ok(())
 }
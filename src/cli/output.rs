@@ -0,0 +1,604 @@
+use anyhow::Result;
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use wdtagger::{pipeline::TaggingResult, tags::TagCategory, threshold::Threshold};
+
+use crate::args::CaptionCategory;
+use crate::file;
+use crate::table::TableWriter;
+
+/// Join the tags of the result into a comma-separated caption in the specified category order.
+pub fn caption(result: &TaggingResult, order: &[CaptionCategory]) -> String {
+    order
+        .iter()
+        .flat_map(|category| match category {
+            // only the most probable rating makes sense in a caption
+            CaptionCategory::Rating => result.rating.keys().take(1).collect::<Vec<_>>(),
+            CaptionCategory::Character => result.character.keys().collect(),
+            CaptionCategory::General => result.general.keys().collect(),
+            CaptionCategory::Artist => result.artist.keys().collect(),
+            CaptionCategory::Copyright => result.copyright.keys().collect(),
+            CaptionCategory::Meta => result.meta.keys().collect(),
+        })
+        .map(|tag| tag.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Writer of the caption sidecar files.
+#[derive(Debug, Clone)]
+pub struct CaptionWriter {
+    /// Root folders of the input images
+    roots: Vec<PathBuf>,
+    /// Folder to mirror the input tree into. Next to each image if `None`.
+    output_dir: Option<PathBuf>,
+    extension: String,
+    order: Vec<CaptionCategory>,
+    /// Images whose caption path is taken by an earlier image, and that earlier image
+    conflicts: HashMap<PathBuf, PathBuf>,
+}
+
+impl CaptionWriter {
+    pub fn new(
+        roots: Vec<PathBuf>,
+        output_dir: Option<PathBuf>,
+        extension: &str,
+        order: Vec<CaptionCategory>,
+    ) -> Self {
+        Self {
+            roots,
+            output_dir,
+            extension: extension.trim_start_matches('.').to_string(),
+            order,
+            conflicts: HashMap::new(),
+        }
+    }
+
+    /// Find the images whose caption path is the same as the one of an earlier image in `images`,
+    /// e.g. `a.png` and `a.jpg` in a folder. The first image keeps the caption,
+    /// and writing the captions of the others fails instead of overwriting it.
+    pub fn with_images(mut self, images: &[PathBuf]) -> Self {
+        let mut owners = HashMap::new();
+        for image in images {
+            let owner = owners.entry(self.caption_path(image)).or_insert(image);
+            if *owner != image {
+                self.conflicts.insert(image.clone(), owner.clone());
+            }
+        }
+        self
+    }
+
+    /// Check if the caption path of the image is taken by an earlier image.
+    pub fn has_conflict(&self, image: &Path) -> bool {
+        self.conflicts.contains_key(image)
+    }
+
+    /// Get the path of the image relative to the deepest root that contains it.
+    /// Paths outside of the roots keep their folders without the root and the `..` components,
+    /// so that the captions stay in the output folder and do not overwrite each other.
+    fn relative_path(&self, image: &Path) -> PathBuf {
+        let relative = self
+            .roots
+            .iter()
+            .filter_map(|root| image.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.components().count())
+            .unwrap_or(image);
+
+        let mut path = PathBuf::new();
+        for component in relative.components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::ParentDir => {
+                    path.pop();
+                }
+                Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+            }
+        }
+        path
+    }
+
+    /// Get the caption path for the image.
+    pub fn caption_path(&self, image: &Path) -> PathBuf {
+        let path = match &self.output_dir {
+            Some(output_dir) => output_dir.join(self.relative_path(image)),
+            None => image.to_path_buf(),
+        };
+        path.with_extension(&self.extension)
+    }
+
+    /// Write the caption of the image.
+    pub async fn write(&self, image: &Path, result: &TaggingResult) -> Result<()> {
+        self.write_bytes(image, caption(result, &self.order).as_bytes())
+            .await
+    }
+
+    /// Write the bytes into the sidecar file of the image, e.g. its embedding.
+    pub async fn write_bytes(&self, image: &Path, bytes: &[u8]) -> Result<()> {
+        let path = self.caption_path(image);
+        if let Some(owner) = self.conflicts.get(image) {
+            anyhow::bail!(
+                "{} is also the sidecar file of {}",
+                path.display(),
+                owner.display()
+            );
+        }
+        if let Some(parent) = path.parent() {
+            file::create_dir(parent).await?;
+        }
+        file::write_bytes_to_file(bytes, &path).await
+    }
+}
+
+/// Model used for the tagging
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    /// Repository id, or the local folder of the model
+    pub repo_id: String,
+    /// Requested revision, `main` if `None`
+    pub revision: Option<String>,
+    /// Commit hash that the revision was resolved to
+    pub commit: Option<String>,
+}
+
+/// Record of a tagged image
+#[derive(Debug, Serialize)]
+pub struct Record<'a> {
+    pub path: String,
+    pub model: &'a ModelInfo,
+    /// Threshold of each category, a number or `"mcut"`. Categories not filtered are omitted.
+    pub thresholds: &'a IndexMap<&'static str, Value>,
+    pub tags: &'a TaggingResult,
+    /// Time the record was written, in milliseconds since the Unix epoch
+    pub tagged_at: u64,
+}
+
+/// Describe the threshold of each category for the records.
+pub fn describe_thresholds(threshold: &Threshold) -> IndexMap<&'static str, Value> {
+    TagCategory::all()
+        .iter()
+        .filter_map(|category| {
+            let value = match threshold.for_category(category)? {
+                Threshold::Fixed(value) => json!(value),
+                Threshold::MCut => json!("mcut"),
+                Threshold::PerCategory(_) => return None,
+            };
+            Some((category.name(), value))
+        })
+        .collect()
+}
+
+/// Writer of the JSON or JSON Lines records.
+pub struct JsonWriter {
+    sink: Box<dyn Write + Send>,
+    /// Output file, `None` for stdout
+    path: Option<PathBuf>,
+    model: ModelInfo,
+    thresholds: IndexMap<&'static str, Value>,
+    /// Write a record per line instead of an array
+    lines: bool,
+    /// Number of the records written into the array
+    written: usize,
+    /// Images that have a record from a previous run, and the time of their latest record
+    resumed: HashMap<PathBuf, u64>,
+}
+
+impl JsonWriter {
+    /// Write into the file, or stdout if `None`.
+    /// With `append`, the JSON Lines records are appended to the existing file to resume a previous run.
+    pub fn new(
+        path: Option<&Path>,
+        model: ModelInfo,
+        threshold: &Threshold,
+        lines: bool,
+        append: bool,
+    ) -> Result<Self> {
+        let mut resumed = HashMap::new();
+        let mut sink: Box<dyn Write + Send> = match path {
+            Some(path) if lines && append && path.is_file() => {
+                resumed = Self::resume(path)?;
+                Box::new(BufWriter::new(
+                    std::fs::OpenOptions::new().append(true).open(path)?,
+                ))
+            }
+            Some(path) => Box::new(BufWriter::new(std::fs::File::create(path)?)),
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        // the array is streamed, so that the records are not kept in memory
+        if !lines {
+            sink.write_all(b"[")?;
+        }
+
+        Ok(Self {
+            sink,
+            path: path.map(|path| path.to_path_buf()),
+            model,
+            thresholds: describe_thresholds(threshold),
+            lines,
+            written: 0,
+            resumed,
+        })
+    }
+
+    /// Read the paths of the images recorded in the JSON Lines file and the time of their records,
+    /// and drop the last record if it was cut off while being written.
+    fn resume(path: &Path) -> Result<HashMap<PathBuf, u64>> {
+        let text = std::fs::read_to_string(path)?;
+        let complete = match text.rfind('\n') {
+            Some(end) => &text[..=end],
+            None => "",
+        };
+        if complete.len() < text.len() {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(complete.len() as u64)?;
+        }
+
+        let mut resumed = HashMap::new();
+        for record in complete
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        {
+            // the records without the time are tagged again
+            if let (Some(path), Some(tagged_at)) =
+                (record["path"].as_str(), record["tagged_at"].as_u64())
+            {
+                let latest = resumed.entry(PathBuf::from(path)).or_insert(tagged_at);
+                *latest = tagged_at.max(*latest);
+            }
+        }
+        Ok(resumed)
+    }
+
+    /// Check if the image has a record from a previous run that is newer than the image.
+    pub async fn is_done(&self, image: &Path) -> bool {
+        match self.resumed.get(image) {
+            Some(tagged_at) => file::is_unmodified_since(image, *tagged_at).await,
+            None => false,
+        }
+    }
+
+    /// Check if the records can be appended to resume an interrupted run.
+    pub fn is_resumable(&self) -> bool {
+        self.lines && self.path.is_some()
+    }
+
+    /// Write the record of the image.
+    pub fn write(&mut self, image: &Path, result: &TaggingResult) -> Result<()> {
+        let record = Record {
+            path: image.display().to_string(),
+            model: &self.model,
+            thresholds: &self.thresholds,
+            tags: result,
+            tagged_at: file::unix_millis(SystemTime::now()),
+        };
+
+        match self.lines {
+            true => {
+                serde_json::to_writer(&mut self.sink, &record)?;
+                self.sink.write_all(b"\n")?;
+            }
+            false => {
+                let separator: &[u8] = match self.written {
+                    0 => b"\n",
+                    _ => b",\n",
+                };
+                self.sink.write_all(separator)?;
+                serde_json::to_writer_pretty(&mut self.sink, &record)?;
+                self.written += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the written records.
+    pub fn flush(&mut self) -> Result<()> {
+        self.sink.flush()?;
+        Ok(())
+    }
+
+    /// Close the array and flush.
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.lines {
+            let end: &[u8] = match self.written {
+                0 => b"]\n",
+                _ => b"\n]\n",
+            };
+            self.sink.write_all(end)?;
+        }
+        self.sink.flush()?;
+        Ok(())
+    }
+}
+
+/// Destination of the tagging results.
+pub enum Output {
+    /// Print the results for debugging
+    Debug,
+    /// Write a caption file per image
+    Caption(CaptionWriter),
+    /// Write JSON or JSON Lines records
+    Json(JsonWriter),
+    /// Write a table of the results
+    Table(Box<TableWriter>),
+}
+
+impl Output {
+    /// Write the result of the image.
+    pub async fn write(&mut self, image: &Path, result: &TaggingResult) -> Result<()> {
+        match self {
+            Output::Debug => {
+                println!("{}: {:#?}", image.display(), result);
+                Ok(())
+            }
+            Output::Caption(writer) => writer.write(image, result).await,
+            Output::Json(writer) => writer.write(image, result),
+            Output::Table(writer) => writer.write(image, result),
+        }
+    }
+
+    /// Check if the image already has an up-to-date result from a previous run.
+    pub async fn is_done(&self, image: &Path) -> bool {
+        match self {
+            Output::Caption(writer) => {
+                !writer.has_conflict(image)
+                    && file::is_up_to_date(writer.caption_path(image), image).await
+            }
+            Output::Json(writer) => writer.is_done(image).await,
+            _ => false,
+        }
+    }
+
+    /// Check if the output can be resumed after an interruption.
+    pub fn is_resumable(&self) -> bool {
+        match self {
+            Output::Caption(_) => true,
+            Output::Json(writer) => writer.is_resumable(),
+            _ => false,
+        }
+    }
+
+    /// Flush the written results so that they survive an interruption.
+    pub fn flush(&mut self) -> Result<()> {
+        match self {
+            Output::Json(writer) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finish writing the results.
+    pub fn finish(&mut self) -> Result<()> {
+        match self {
+            Output::Json(writer) => writer.finish(),
+            Output::Table(writer) => writer.finish(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::TempDir;
+    use std::time::Duration;
+
+    fn model_info() -> ModelInfo {
+        ModelInfo {
+            repo_id: "model".to_string(),
+            revision: None,
+            commit: None,
+        }
+    }
+
+    fn json_writer(path: &Path) -> JsonWriter {
+        JsonWriter::new(Some(path), model_info(), &Threshold::default(), true, true).unwrap()
+    }
+
+    fn tags() -> TaggingResult {
+        TaggingResult {
+            rating: IndexMap::new(),
+            character: IndexMap::new(),
+            general: IndexMap::from([("1girl".to_string(), 0.9)]),
+            artist: IndexMap::new(),
+            copyright: IndexMap::new(),
+            meta: IndexMap::new(),
+        }
+    }
+
+    /// Set the modification time of the file relative to now.
+    fn touch(path: &Path, offset_secs: i64) {
+        let now = SystemTime::now();
+        let time = match offset_secs >= 0 {
+            true => now + Duration::from_secs(offset_secs as u64),
+            false => now - Duration::from_secs(offset_secs.unsigned_abs()),
+        };
+        std::fs::File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_json_array() {
+        let temp = TempDir::new("array");
+        let dir = temp.path();
+        let path = dir.join("tags.json");
+
+        let mut writer = JsonWriter::new(
+            Some(&path),
+            model_info(),
+            &Threshold::default(),
+            false,
+            true,
+        )
+        .unwrap();
+        writer.write(Path::new("a.png"), &tags()).unwrap();
+        writer.flush().unwrap();
+        // the records are on disk before the array is closed
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("[\n{") && text.contains("a.png"));
+
+        writer.write(Path::new("b.png"), &tags()).unwrap();
+        writer.finish().unwrap();
+        let records: Vec<Value> =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["path"], "b.png");
+        assert_eq!(records[0]["tags"]["general"]["1girl"], json!(0.9));
+
+        let mut writer = JsonWriter::new(
+            Some(&path),
+            model_info(),
+            &Threshold::default(),
+            false,
+            true,
+        )
+        .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "[]\n");
+    }
+
+    #[tokio::test]
+    async fn test_resume_skips_tagged_images() {
+        let temp = TempDir::new("resume");
+        let dir = temp.path();
+        let (a, b, c) = (dir.join("a.png"), dir.join("b.png"), dir.join("c.png"));
+        touch(&a, -3600);
+        touch(&b, -3600);
+        touch(&c, -3600);
+        let records = dir.join("tags.jsonl");
+
+        let mut writer = json_writer(&records);
+        writer.write(&a, &tags()).unwrap();
+        writer.write(&b, &tags()).unwrap();
+        writer.finish().unwrap();
+        // a record cut off by an interruption
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&records)
+            .unwrap()
+            .write_all(b"{\"path\":")
+            .unwrap();
+
+        let writer = json_writer(&records);
+        assert!(writer.is_done(&a).await);
+        assert!(writer.is_done(&b).await);
+        assert!(!writer.is_done(&c).await);
+        assert!(std::fs::read_to_string(&records).unwrap().ends_with('\n'));
+    }
+
+    #[tokio::test]
+    async fn test_resume_retags_edited_images() {
+        let temp = TempDir::new("retag");
+        let dir = temp.path();
+        let (a, b) = (dir.join("a.png"), dir.join("b.png"));
+        touch(&a, -3600);
+        touch(&b, -3600);
+        let records = dir.join("tags.jsonl");
+
+        let mut writer = json_writer(&records);
+        writer.write(&a, &tags()).unwrap();
+        writer.finish().unwrap();
+
+        // the image is edited after its record, then another run appends to the file
+        touch(&a, 60);
+        let mut writer = json_writer(&records);
+        writer.write(&b, &tags()).unwrap();
+        writer.finish().unwrap();
+        touch(&records, 120);
+
+        let writer = json_writer(&records);
+        assert!(!writer.is_done(&a).await);
+        assert!(writer.is_done(&b).await);
+    }
+
+    fn caption_writer(roots: &[&str]) -> CaptionWriter {
+        CaptionWriter::new(
+            roots.iter().map(PathBuf::from).collect(),
+            Some(PathBuf::from("/out")),
+            "txt",
+            vec![CaptionCategory::General],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_caption_conflicts() {
+        let temp = TempDir::new("conflicts");
+        let dir = temp.path();
+        let images = [
+            dir.join("x/a.png"),
+            dir.join("x/a.jpg"),
+            dir.join("x/b.png"),
+            // the same relative path in another root
+            dir.join("y/a.webp"),
+        ];
+        let writer = CaptionWriter::new(
+            vec![dir.join("x"), dir.join("y")],
+            Some(dir.join("out")),
+            "txt",
+            vec![CaptionCategory::General],
+        )
+        .with_images(&images);
+
+        assert!(!writer.has_conflict(&images[0]));
+        assert!(writer.has_conflict(&images[1]));
+        assert!(!writer.has_conflict(&images[2]));
+        assert!(writer.has_conflict(&images[3]));
+
+        writer.write(&images[0], &tags()).await.unwrap();
+        assert!(writer.write(&images[1], &tags()).await.is_err());
+        assert!(writer.write(&images[3], &tags()).await.is_err());
+        let caption = std::fs::read_to_string(dir.join("out/a.txt")).unwrap();
+        assert_eq!(caption, "1girl");
+
+        // the images of a conflict are never done, so they are reported on every run
+        let output = Output::Caption(writer);
+        std::fs::create_dir_all(dir.join("y")).unwrap();
+        touch(&images[3], -3600);
+        assert!(!output.is_done(&images[3]).await);
+    }
+
+    #[test]
+    fn test_caption_path_in_roots() {
+        let writer = caption_writer(&["/data", "/data/sub"]);
+        assert_eq!(
+            writer.caption_path(Path::new("/data/sub/a.png")),
+            PathBuf::from("/out/a.txt")
+        );
+        assert_eq!(
+            writer.caption_path(Path::new("/data/x/a.png")),
+            PathBuf::from("/out/x/a.txt")
+        );
+    }
+
+    #[test]
+    fn test_caption_path_parent_dir() {
+        let writer = caption_writer(&["images"]);
+        assert_eq!(
+            writer.caption_path(Path::new("../other/a.png")),
+            PathBuf::from("/out/other/a.txt")
+        );
+        assert_eq!(
+            writer.caption_path(Path::new("./a/../../../b.png")),
+            PathBuf::from("/out/b.txt")
+        );
+    }
+
+    #[test]
+    fn test_caption_path_outside_roots() {
+        let writer = caption_writer(&["/data"]);
+        let x = writer.caption_path(Path::new("/elsewhere/x/a.png"));
+        let y = writer.caption_path(Path::new("/elsewhere/y/a.png"));
+        assert_eq!(x, PathBuf::from("/out/elsewhere/x/a.txt"));
+        assert_ne!(x, y);
+    }
+}
@@ -0,0 +1,341 @@
+use anyhow::Result;
+use indexmap::IndexMap;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use wdtagger::{pipeline::TaggingResult, tags::TagCategory};
+
+use crate::batch::Timings;
+
+/// Number of the most frequent tags in the summary
+const TOP_TAGS: usize = 10;
+
+/// Create a progress bar of the images, hidden unless stdout and stderr are terminals.
+pub fn progress_bar(len: usize, enabled: bool) -> ProgressBar {
+    let visible = enabled && std::io::stdout().is_terminal() && std::io::stderr().is_terminal();
+    let progress = match visible {
+        true => ProgressBar::new(len as u64),
+        false => ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::hidden()),
+    };
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{elapsed_precise} [{wide_bar}] {pos}/{len} ({per_sec}, ETA {eta})",
+        )
+        .expect("valid template")
+        .progress_chars("=> "),
+    );
+    progress
+}
+
+/// Total time spent in a stage
+#[derive(Debug, Clone, Copy, Default)]
+struct Latency {
+    total: Duration,
+    count: u32,
+}
+
+impl Latency {
+    fn add(&mut self, duration: Duration) {
+        self.total += duration;
+        self.count += 1;
+    }
+
+    /// Mean duration in milliseconds
+    fn mean_ms(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.total.as_secs_f64() * 1000.0 / count as f64,
+        }
+    }
+}
+
+/// Statistics collected during the run
+#[derive(Debug)]
+pub struct Summary {
+    start: Instant,
+    succeeded: usize,
+    skipped: usize,
+    failed: usize,
+    decode: Latency,
+    preprocess: Latency,
+    infer: Latency,
+    write: Latency,
+    /// Number of images of each (category, tag)
+    tag_counts: HashMap<(TagCategory, String), usize>,
+}
+
+impl Summary {
+    pub fn new(skipped: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            succeeded: 0,
+            skipped,
+            failed: 0,
+            decode: Latency::default(),
+            preprocess: Latency::default(),
+            infer: Latency::default(),
+            write: Latency::default(),
+            tag_counts: HashMap::new(),
+        }
+    }
+
+    /// Record an image that was processed and written.
+    pub fn succeeded(&mut self, timings: &Timings, write: Duration) {
+        self.succeeded += 1;
+        self.decode.add(timings.decode);
+        self.preprocess.add(timings.preprocess);
+        self.infer.add(timings.infer);
+        self.write.add(write);
+    }
+
+    /// Count the tags of a tagged image.
+    pub fn count_tags(&mut self, result: &TaggingResult) {
+        // every image has a rating, so it is not worth counting
+        for category in TagCategory::all() {
+            if category == TagCategory::Rating {
+                continue;
+            }
+            for tag in result.get(&category).keys() {
+                *self
+                    .tag_counts
+                    .entry((category.clone(), tag.clone()))
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    /// Record an image that failed.
+    pub fn failed(&mut self) {
+        self.failed += 1;
+    }
+
+    /// Create the report of the run.
+    pub fn report(&self) -> Report {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let processed = self.succeeded + self.failed;
+
+        let mut top_tags = self
+            .tag_counts
+            .iter()
+            .map(|((category, tag), count)| TagCount {
+                tag: tag.clone(),
+                category: category.name(),
+                count: *count,
+            })
+            .collect::<Vec<_>>();
+        top_tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        top_tags.truncate(TOP_TAGS);
+
+        Report {
+            succeeded: self.succeeded,
+            skipped: self.skipped,
+            failed: self.failed,
+            elapsed_secs: elapsed,
+            images_per_sec: match elapsed > 0.0 {
+                true => processed as f64 / elapsed,
+                false => 0.0,
+            },
+            latency_ms: IndexMap::from([
+                ("decode", self.decode.mean_ms()),
+                ("preprocess", self.preprocess.mean_ms()),
+                ("infer", self.infer.mean_ms()),
+                ("write", self.write.mean_ms()),
+            ]),
+            top_tags,
+        }
+    }
+}
+
+/// Number of images that have the tag
+#[derive(Debug, Clone, Serialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub category: &'static str,
+    pub count: usize,
+}
+
+/// Summary of the run
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub elapsed_secs: f64,
+    pub images_per_sec: f64,
+    /// Mean latency per image of each stage in milliseconds.
+    /// The inference of a batch is divided among its images.
+    pub latency_ms: IndexMap<&'static str, f64>,
+    /// Most frequent tags except the ratings
+    pub top_tags: Vec<TagCount>,
+}
+
+impl Report {
+    /// Print the report to stderr.
+    pub fn print(&self) {
+        eprintln!(
+            "Tagged {} images, skipped {}, failed {} in {:.1}s ({:.2} images/s)",
+            self.succeeded, self.skipped, self.failed, self.elapsed_secs, self.images_per_sec
+        );
+        eprintln!(
+            "Mean latency per image: {}",
+            self.latency_ms
+                .iter()
+                .map(|(stage, ms)| format!("{} {:.1}ms", stage, ms))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if !self.top_tags.is_empty() {
+            eprintln!("Most frequent tags:");
+            for tag in &self.top_tags {
+                eprintln!("  {:>8}  {} ({})", tag.count, tag.tag, tag.category);
+            }
+        }
+    }
+
+    /// Write the report into a JSON file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::TempDir;
+    use serde_json::Value;
+
+    fn timings(decode_ms: u64, preprocess_ms: u64, infer_ms: u64) -> Timings {
+        Timings {
+            decode: Duration::from_millis(decode_ms),
+            preprocess: Duration::from_millis(preprocess_ms),
+            infer: Duration::from_millis(infer_ms),
+        }
+    }
+
+    fn tags(general: &[&str]) -> TaggingResult {
+        TaggingResult {
+            rating: IndexMap::from([("general".to_string(), 0.9)]),
+            character: IndexMap::new(),
+            general: general.iter().map(|tag| (tag.to_string(), 0.5)).collect(),
+            artist: IndexMap::new(),
+            copyright: IndexMap::new(),
+            meta: IndexMap::new(),
+        }
+    }
+
+    /// Summary of 2 tagged images, 1 failed and 4 skipped
+    fn summary() -> Summary {
+        let mut summary = Summary::new(4);
+        summary.succeeded(&timings(10, 2, 100), Duration::from_millis(1));
+        summary.count_tags(&tags(&["1girl", "solo"]));
+        summary.succeeded(&timings(30, 4, 200), Duration::from_millis(3));
+        summary.count_tags(&tags(&["solo"]));
+        summary.failed();
+        summary
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let report = summary().report();
+        assert_eq!((report.succeeded, report.failed, report.skipped), (2, 1, 4));
+        assert!(report.elapsed_secs > 0.0);
+        // the skipped images are not processed
+        assert_close(report.images_per_sec, 3.0 / report.elapsed_secs);
+
+        // the failed image has no timings
+        assert_eq!(
+            report.latency_ms.keys().copied().collect::<Vec<_>>(),
+            vec!["decode", "preprocess", "infer", "write"]
+        );
+        assert_close(report.latency_ms["decode"], 20.0);
+        assert_close(report.latency_ms["preprocess"], 3.0);
+        assert_close(report.latency_ms["infer"], 150.0);
+        assert_close(report.latency_ms["write"], 2.0);
+
+        // the ratings are not counted
+        assert_eq!(
+            report
+                .top_tags
+                .iter()
+                .map(|tag| (tag.tag.as_str(), tag.category, tag.count))
+                .collect::<Vec<_>>(),
+            vec![("solo", "general", 2), ("1girl", "general", 1)]
+        );
+    }
+
+    #[test]
+    fn test_report_empty() {
+        let report = Summary::new(3).report();
+        assert_eq!((report.succeeded, report.failed, report.skipped), (0, 0, 3));
+        assert_eq!(report.images_per_sec, 0.0);
+        assert!(report.latency_ms.values().all(|ms| *ms == 0.0));
+        assert!(report.top_tags.is_empty());
+    }
+
+    #[test]
+    fn test_top_tags_truncated() {
+        let mut summary = Summary::new(0);
+        let names = (0..TOP_TAGS + 5)
+            .map(|idx| format!("tag_{:02}", idx))
+            .collect::<Vec<_>>();
+        summary.count_tags(&tags(&names.iter().map(String::as_str).collect::<Vec<_>>()));
+
+        let top_tags = summary.report().top_tags;
+        assert_eq!(top_tags.len(), TOP_TAGS);
+        // the ties are in the order of the names
+        assert_eq!(top_tags[0].tag, "tag_00");
+        assert_eq!(
+            top_tags[TOP_TAGS - 1].tag,
+            format!("tag_{:02}", TOP_TAGS - 1)
+        );
+    }
+
+    #[test]
+    fn test_save() {
+        let temp = TempDir::new("report-save");
+        let path = temp.path().join("report.json");
+        summary().report().save(&path).unwrap();
+
+        let json: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let keys = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                "elapsed_secs",
+                "failed",
+                "images_per_sec",
+                "latency_ms",
+                "skipped",
+                "succeeded",
+                "top_tags"
+            ]
+        );
+        assert_eq!(json["succeeded"], 2);
+        assert_eq!(json["skipped"], 4);
+        assert_eq!(json["failed"], 1);
+        assert_eq!(json["latency_ms"]["infer"], 150.0);
+        assert_eq!(
+            json["top_tags"][0],
+            serde_json::json!({"tag": "solo", "category": "general", "count": 2})
+        );
+    }
+}
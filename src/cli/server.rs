@@ -0,0 +1,657 @@
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, FromRequest, Multipart, Query, Request, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use ndarray::{Array, Axis, Ix4};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::spawn_blocking;
+use wdtagger::{
+    batch::AdaptiveBatcher,
+    error::TaggerError,
+    file::HfOptions,
+    pipeline::{TaggingPipeline, TaggingResult},
+    processor::ImageProcessor,
+    registry::ModelRegistry,
+    tags::TagCategory,
+    threshold::Threshold,
+};
+
+use crate::args::{InputOutput, ServeArgs};
+use crate::output::describe_thresholds;
+
+/// Serve the models of the registry until the process is stopped.
+/// The default model is loaded at startup, and the presets and the other repositories on their first request.
+pub async fn serve(
+    registry: ModelRegistry,
+    default_model: &str,
+    hf_options: HfOptions,
+    args: &ServeArgs,
+    io: &InputOutput,
+) -> Result<()> {
+    // the revision is only for the default model
+    let mut registry = registry
+        .with_presets()
+        .with_hf_options(HfOptions {
+            revision: None,
+            ..hf_options
+        })
+        .with_auto_register(args.allow_hub_models)
+        .with_batcher(io.batcher(args.max_batch_size));
+    if let Some(budget) = args.memory_budget_mb {
+        registry = registry.with_memory_budget(budget * 1024 * 1024);
+    }
+    let pipeline = registry.get(default_model)?;
+    eprintln!("Device: <{}>", pipeline.model.device());
+
+    let (jobs_tx, jobs_rx) = mpsc::channel(args.max_batch_size * 4);
+    tokio::spawn(batcher(
+        jobs_rx,
+        args.max_batch_size,
+        Duration::from_millis(args.batch_wait_ms),
+    ));
+
+    let state = AppState {
+        registry: Arc::new(registry),
+        default_model: default_model.to_string(),
+        jobs: jobs_tx,
+    };
+    let app = router(state, args.body_limit_mb * 1024 * 1024);
+
+    let listener = tokio::net::TcpListener::bind((args.host.as_str(), args.port)).await?;
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+/// Routes of the API
+fn router(state: AppState, body_limit: usize) -> Router {
+    Router::new()
+        .route("/tag", post(tag))
+        .route("/tag/batch", post(tag_batch))
+        .route("/models", get(models))
+        .route("/health", get(health))
+        .layer(DefaultBodyLimit::max(body_limit))
+        .with_state(state)
+}
+
+/// Image waiting to be tagged in the next batch
+struct Job {
+    pipe: Arc<TaggingPipeline>,
+    batcher: Arc<AdaptiveBatcher>,
+    tensor: Array<f32, Ix4>,
+    threshold: Threshold,
+    reply: oneshot::Sender<Result<TaggingResult, String>>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    registry: Arc<ModelRegistry>,
+    /// Model used when the request does not specify one
+    default_model: String,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl AppState {
+    /// Get the pipeline of the model and its batcher, loading it if needed.
+    async fn pipeline(
+        &self,
+        name: Option<&str>,
+    ) -> Result<(Arc<TaggingPipeline>, Arc<AdaptiveBatcher>), ApiError> {
+        let registry = self.registry.clone();
+        let name = name.unwrap_or(&self.default_model).to_string();
+        spawn_blocking(move || registry.get_with_batcher(&name))
+            .await
+            .map_err(ApiError::internal)?
+            .map_err(|e| match e {
+                TaggerError::Registry(_) => ApiError {
+                    status: StatusCode::NOT_FOUND,
+                    message: e.to_string(),
+                },
+                _ => ApiError::internal(e),
+            })
+    }
+
+    /// Decode and preprocess the image, and wait for its batch to be tagged.
+    async fn tag(
+        &self,
+        pipe: Arc<TaggingPipeline>,
+        batcher: Arc<AdaptiveBatcher>,
+        bytes: Bytes,
+        threshold: Threshold,
+    ) -> Result<TaggingResult, ApiError> {
+        let tensor = {
+            let pipe = pipe.clone();
+            spawn_blocking(move || {
+                let image = image::load_from_memory(&bytes)
+                    .map_err(|e| format!("Failed to load the image: {}", e))?;
+                pipe.preprocessor.process(&image).map_err(|e| e.to_string())
+            })
+        }
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::bad_request)?;
+
+        let (reply, rx) = oneshot::channel();
+        self.jobs
+            .send(Job {
+                pipe,
+                batcher,
+                tensor,
+                threshold,
+                reply,
+            })
+            .await
+            .map_err(|_| ApiError::internal("The batcher is stopped"))?;
+
+        rx.await
+            .map_err(ApiError::internal)?
+            .map_err(ApiError::internal)
+    }
+}
+
+/// Group the images arriving within `wait` of each other into batches of up to `max_batch_size`.
+async fn batcher(mut rx: mpsc::Receiver<Job>, max_batch_size: usize, wait: Duration) {
+    while let Some(first) = rx.recv().await {
+        let jobs = collect_batch(&mut rx, first, max_batch_size, wait).await;
+
+        let result = spawn_blocking(move || {
+            // a batch per model and input shape, as the tensors of a batch are concatenated
+            let groups = group_by(jobs, |a, b| {
+                Arc::ptr_eq(&a.pipe, &b.pipe) && a.tensor.dim() == b.tensor.dim()
+            });
+            for group in groups {
+                predict(group);
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            eprintln!("The inference task failed: {}", e);
+        }
+    }
+}
+
+/// Receive the items arriving within `wait` of the first one, up to `max_batch_size` in total.
+async fn collect_batch<T>(
+    rx: &mut mpsc::Receiver<T>,
+    first: T,
+    max_batch_size: usize,
+    wait: Duration,
+) -> Vec<T> {
+    let mut items = vec![first];
+    let deadline = tokio::time::Instant::now() + wait;
+    while items.len() < max_batch_size {
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(item)) => items.push(item),
+            _ => break,
+        }
+    }
+    items
+}
+
+/// Group the items in their order, each with the first item of a group it is `same` as.
+fn group_by<T>(items: Vec<T>, same: impl Fn(&T, &T) -> bool) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = vec![];
+    for item in items {
+        match groups.iter_mut().find(|group| same(&group[0], &item)) {
+            Some(group) => group.push(item),
+            None => groups.push(vec![item]),
+        }
+    }
+    groups
+}
+
+/// Tag the batch of a model and reply to each job with its own threshold.
+/// The tensors of the jobs must have the same shape.
+/// The batch is split to fit in the memory, and a bad image does not fail the others.
+fn predict(jobs: Vec<Job>) {
+    let pipe = jobs[0].pipe.clone();
+    let batcher = jobs[0].batcher.clone();
+    let views = jobs.iter().map(|job| job.tensor.view()).collect::<Vec<_>>();
+    let tensor = match ndarray::concatenate(Axis(0), &views) {
+        Ok(tensor) => tensor,
+        Err(e) => {
+            for job in jobs {
+                let _ = job
+                    .reply
+                    .send(Err(format!("Failed to make the batch: {}", e)));
+            }
+            return;
+        }
+    };
+
+    let probs = batcher.run(tensor, |tensor| pipe.predict_probabilities(tensor));
+    for (job, probs) in jobs.into_iter().zip(probs) {
+        let result = probs
+            .map(|probs| pipe.postprocess_with_threshold(&probs, &job.threshold))
+            .map_err(|e| e.to_string());
+        let _ = job.reply.send(result);
+    }
+}
+
+/// Model and per-request overrides of its thresholds
+#[derive(Debug, Default, Deserialize)]
+struct TagQuery {
+    /// Name of the model, or its repository id on Hugging Face
+    model: Option<String>,
+    threshold: Option<f32>,
+    general_threshold: Option<f32>,
+    character_threshold: Option<f32>,
+    #[serde(default)]
+    mcut: bool,
+    #[serde(default)]
+    general_mcut: bool,
+    #[serde(default)]
+    character_mcut: bool,
+}
+
+impl TagQuery {
+    /// Apply the overrides to the threshold of the server.
+    fn apply(&self, threshold: &Threshold) -> Threshold {
+        let general = match self.mcut || self.general_mcut {
+            true => Some(Threshold::MCut),
+            false => self
+                .general_threshold
+                .or(self.threshold)
+                .map(Threshold::Fixed),
+        };
+        let character = match self.mcut || self.character_mcut {
+            true => Some(Threshold::MCut),
+            false => self
+                .character_threshold
+                .or(self.threshold)
+                .map(Threshold::Fixed),
+        };
+
+        let mut threshold = threshold.clone();
+        if let Some(general) = general {
+            threshold = threshold.with_category(TagCategory::General, general);
+        }
+        if let Some(character) = character {
+            threshold = threshold.with_category(TagCategory::Character, character);
+        }
+        threshold
+    }
+}
+
+/// Error response of the API
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request<E: ToString>(error: E) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: error.to_string(),
+        }
+    }
+
+    fn internal<E: ToString>(error: E) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+/// Read the images of the request, either the files of a multipart form or the raw body.
+/// Returns the file name, or the field name, of each image.
+async fn read_images(request: Request) -> Result<Vec<(Option<String>, Bytes)>, ApiError> {
+    let multipart = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+    if !multipart {
+        let bytes = Bytes::from_request(request, &())
+            .await
+            .map_err(|e| ApiError::bad_request(e.body_text()))?;
+        return Ok(vec![(None, bytes)]);
+    }
+
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(|e| ApiError::bad_request(e.body_text()))?;
+    let mut images = vec![];
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(ApiError::bad_request)?
+    {
+        let name = field
+            .file_name()
+            .or(field.name())
+            .map(|name| name.to_string());
+        let bytes = field.bytes().await.map_err(ApiError::bad_request)?;
+        images.push((name, bytes));
+    }
+    Ok(images)
+}
+
+/// Tag an image
+async fn tag(
+    State(state): State<AppState>,
+    Query(query): Query<TagQuery>,
+    request: Request,
+) -> Result<Json<TaggingResult>, ApiError> {
+    let mut images = read_images(request).await?;
+    if images.len() != 1 {
+        return Err(ApiError::bad_request(format!(
+            "Expected an image, got {}",
+            images.len()
+        )));
+    }
+
+    let (_, bytes) = images.remove(0);
+    let (pipe, batcher) = state.pipeline(query.model.as_deref()).await?;
+    let threshold = query.apply(pipe.threshold());
+    Ok(Json(state.tag(pipe, batcher, bytes, threshold).await?))
+}
+
+/// Result of an image of a batch
+#[derive(Debug, Serialize)]
+struct BatchItem {
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<TaggingResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Tag the images of a multipart form. An image that fails does not fail the others.
+async fn tag_batch(
+    State(state): State<AppState>,
+    Query(query): Query<TagQuery>,
+    request: Request,
+) -> Result<Json<Vec<BatchItem>>, ApiError> {
+    let images = read_images(request).await?;
+    let (pipe, batcher) = state.pipeline(query.model.as_deref()).await?;
+    let threshold = query.apply(pipe.threshold());
+
+    let items = futures::future::join_all(images.into_iter().map(|(name, bytes)| {
+        let state = state.clone();
+        let pipe = pipe.clone();
+        let batcher = batcher.clone();
+        let threshold = threshold.clone();
+        async move {
+            match state.tag(pipe, batcher, bytes, threshold).await {
+                Ok(tags) => BatchItem {
+                    name,
+                    tags: Some(tags),
+                    error: None,
+                },
+                Err(e) => BatchItem {
+                    name,
+                    tags: None,
+                    error: Some(e.message),
+                },
+            }
+        }
+    }))
+    .await;
+
+    Ok(Json(items))
+}
+
+/// Get the models that can be served
+async fn models(State(state): State<AppState>) -> Json<Value> {
+    let models = state
+        .registry
+        .models()
+        .into_iter()
+        .map(|model| {
+            json!({
+                "name": model.name,
+                "source": model.source.to_string(),
+                "default": model.name == state.default_model,
+                "loaded": model.memory.is_some(),
+                "memory": model.memory,
+                "thresholds": model.threshold.as_ref().map(describe_thresholds),
+            })
+        })
+        .collect::<Vec<_>>();
+    Json(json!(models))
+}
+
+/// Check if the server is up
+async fn health() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    /// State without any model, for the requests that fail before loading one
+    fn state() -> AppState {
+        let (jobs, _) = mpsc::channel(1);
+        AppState {
+            registry: Arc::new(ModelRegistry::new(vec![])),
+            default_model: "model".to_string(),
+            jobs,
+        }
+    }
+
+    /// Request with a multipart form of the fields, each a name, a file name and the bytes
+    fn multipart(uri: &str, fields: &[(&str, Option<&str>, &[u8])]) -> Request {
+        let mut body = vec![];
+        for (name, file_name, bytes) in fields {
+            body.extend(b"--BOUNDARY\r\n");
+            let disposition = match file_name {
+                Some(file_name) => format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n",
+                    name, file_name
+                ),
+                None => format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name),
+            };
+            body.extend(disposition.as_bytes());
+            body.extend(*bytes);
+            body.extend(b"\r\n");
+        }
+        body.extend(b"--BOUNDARY--\r\n");
+
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn body_json(response: Response) -> Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_query_threshold() {
+        let server = Threshold::default();
+        assert_eq!(TagQuery::default().apply(&server), server);
+
+        let query = TagQuery {
+            threshold: Some(0.5),
+            general_threshold: Some(0.7),
+            ..Default::default()
+        };
+        let threshold = query.apply(&server);
+        // the threshold of the category takes precedence over the common one
+        assert_eq!(
+            threshold.for_category(&TagCategory::General),
+            Some(&Threshold::Fixed(0.7))
+        );
+        assert_eq!(
+            threshold.for_category(&TagCategory::Character),
+            Some(&Threshold::Fixed(0.5))
+        );
+        // the other categories keep the threshold of the server
+        assert_eq!(
+            threshold.for_category(&TagCategory::Rating),
+            server.for_category(&TagCategory::Rating)
+        );
+        assert_eq!(
+            threshold.for_category(&TagCategory::Meta),
+            server.for_category(&TagCategory::Meta)
+        );
+
+        let query = TagQuery {
+            threshold: Some(0.5),
+            general_threshold: Some(0.7),
+            character_threshold: Some(0.9),
+            mcut: true,
+            ..Default::default()
+        };
+        let threshold = query.apply(&server);
+        assert_eq!(
+            threshold.for_category(&TagCategory::General),
+            Some(&Threshold::MCut)
+        );
+        assert_eq!(
+            threshold.for_category(&TagCategory::Character),
+            Some(&Threshold::MCut)
+        );
+
+        let query = TagQuery {
+            character_threshold: Some(0.9),
+            general_mcut: true,
+            ..Default::default()
+        };
+        let threshold = query.apply(&server);
+        assert_eq!(
+            threshold.for_category(&TagCategory::General),
+            Some(&Threshold::MCut)
+        );
+        assert_eq!(
+            threshold.for_category(&TagCategory::Character),
+            Some(&Threshold::Fixed(0.9))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_images_raw() {
+        let request = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, "image/png")
+            .body(Body::from("image"))
+            .unwrap();
+        let images = read_images(request).await.ok().unwrap();
+        assert_eq!(images, vec![(None, Bytes::from("image"))]);
+    }
+
+    #[tokio::test]
+    async fn test_read_images_multipart() {
+        let request = multipart(
+            "/tag/batch",
+            &[
+                ("files", Some("a.png"), b"first"),
+                // the field name when there is no file name
+                ("second", None, b"second"),
+            ],
+        );
+        let images = read_images(request).await.ok().unwrap();
+        assert_eq!(
+            images,
+            vec![
+                (Some("a.png".to_string()), Bytes::from("first")),
+                (Some("second".to_string()), Bytes::from("second")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tag_image_count() {
+        for fields in [
+            vec![],
+            vec![
+                ("a", Some("a.png"), &b"a"[..]),
+                ("b", Some("b.png"), &b"b"[..]),
+            ],
+        ] {
+            let response = router(state(), 1024)
+                .oneshot(multipart("/tag", &fields))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                body_json(response).await["error"],
+                format!("Expected an image, got {}", fields.len())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let request = Request::builder()
+            .uri("/health")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state(), 1024).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await, json!({ "status": "ok" }));
+    }
+
+    #[tokio::test]
+    async fn test_collect_batch() {
+        let (tx, mut rx) = mpsc::channel(8);
+        for idx in 1..=4 {
+            tx.send(idx).await.unwrap();
+        }
+        let wait = Duration::from_millis(10);
+
+        // the batch is full without waiting
+        let first = rx.recv().await.unwrap();
+        assert_eq!(collect_batch(&mut rx, first, 3, wait).await, vec![1, 2, 3]);
+        // the rest after the wait
+        let first = rx.recv().await.unwrap();
+        assert_eq!(collect_batch(&mut rx, first, 3, wait).await, vec![4]);
+
+        drop(tx);
+        assert_eq!(collect_batch(&mut rx, 5, 3, wait).await, vec![5]);
+    }
+
+    #[test]
+    fn test_group_by_model_and_shape() {
+        let (a, b) = (Arc::new("a"), Arc::new("b"));
+        let jobs = vec![
+            (a.clone(), 1, (1, 448, 448, 3)),
+            (b.clone(), 2, (1, 448, 448, 3)),
+            (a.clone(), 3, (1, 448, 448, 3)),
+            // the same model with another input size
+            (a.clone(), 4, (1, 384, 384, 3)),
+            (b.clone(), 5, (1, 448, 448, 3)),
+        ];
+        let groups = group_by(jobs, |x, y| Arc::ptr_eq(&x.0, &y.0) && x.2 == y.2);
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| group.iter().map(|job| job.1).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec![1, 3], vec![2, 5], vec![4]]
+        );
+
+        // another model with the same name is another group
+        let jobs = vec![(a.clone(), 1), (Arc::new("a"), 2)];
+        assert_eq!(group_by(jobs, |x, y| Arc::ptr_eq(&x.0, &y.0)).len(), 2);
+    }
+}
@@ -0,0 +1,387 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use wdtagger::{
+    pipeline::TaggingResult,
+    tags::{LabelTags, TagCategory},
+};
+
+use crate::args::TableLayout;
+
+/// A row of the long table
+#[derive(Debug, Clone, PartialEq)]
+pub struct LongRow {
+    pub path: String,
+    pub tag: String,
+    pub category: &'static str,
+    pub probability: f32,
+}
+
+/// Flatten the result into rows of (image, tag, category, probability) in the order of the model output.
+pub fn long_rows(
+    image: &Path,
+    result: &TaggingResult,
+    label2idx: &HashMap<String, usize>,
+) -> Vec<LongRow> {
+    let mut rows = TagCategory::all()
+        .iter()
+        .flat_map(|category| {
+            result.get(category).iter().map(|(tag, prob)| LongRow {
+                path: image.display().to_string(),
+                tag: tag.clone(),
+                category: category.name(),
+                probability: *prob,
+            })
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by_key(|row| label2idx.get(&row.tag).copied().unwrap_or(usize::MAX));
+    rows
+}
+
+/// Writer of the tabular export of the results.
+pub struct TableWriter {
+    sink: TableSink,
+    layout: TableLayout,
+    /// Tag names in the order of the model output
+    labels: Vec<String>,
+    label2idx: HashMap<String, usize>,
+}
+
+enum TableSink {
+    Csv(csv::Writer<Box<dyn Write + Send>>),
+    #[cfg(feature = "parquet")]
+    Parquet(columnar::ParquetSink),
+}
+
+impl TableWriter {
+    /// Write CSV into the file, or stdout if `None`.
+    pub fn csv(path: Option<&Path>, layout: TableLayout, tags: &LabelTags) -> Result<Self> {
+        let sink: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(BufWriter::new(std::fs::File::create(path)?)),
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        let mut writer = Self::new(TableSink::Csv(csv::Writer::from_writer(sink)), layout, tags);
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    /// Write Parquet into the file. Only the long layout is supported.
+    #[cfg(feature = "parquet")]
+    pub fn parquet(path: Option<&Path>, layout: TableLayout, tags: &LabelTags) -> Result<Self> {
+        let Some(path) = path else {
+            anyhow::bail!("--output is required for the parquet format");
+        };
+        if layout != TableLayout::Long {
+            anyhow::bail!("Only the long layout is supported for the parquet format");
+        }
+        let sink = columnar::ParquetSink::new(path)?;
+        Ok(Self::new(TableSink::Parquet(sink), layout, tags))
+    }
+
+    fn new(sink: TableSink, layout: TableLayout, tags: &LabelTags) -> Self {
+        let labels = tags
+            .tags_in_order()
+            .iter()
+            .map(|tag| tag.name())
+            .collect::<Vec<_>>();
+        let label2idx = labels
+            .iter()
+            .enumerate()
+            .map(|(idx, label)| (label.clone(), idx))
+            .collect();
+
+        Self {
+            sink,
+            layout,
+            labels,
+            label2idx,
+        }
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        match &mut self.sink {
+            TableSink::Csv(csv) => match self.layout {
+                TableLayout::Long => {
+                    csv.write_record(["path", "tag", "category", "probability"])?
+                }
+                TableLayout::Wide => csv.write_record(
+                    std::iter::once("path").chain(self.labels.iter().map(|label| label.as_str())),
+                )?,
+            },
+            #[cfg(feature = "parquet")]
+            TableSink::Parquet(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Write the rows of the image.
+    pub fn write(&mut self, image: &Path, result: &TaggingResult) -> Result<()> {
+        match (&mut self.sink, &self.layout) {
+            (TableSink::Csv(csv), TableLayout::Long) => {
+                for row in long_rows(image, result, &self.label2idx) {
+                    csv.write_record([
+                        row.path,
+                        row.tag,
+                        row.category.to_string(),
+                        row.probability.to_string(),
+                    ])?;
+                }
+            }
+            (TableSink::Csv(csv), TableLayout::Wide) => {
+                // empty cells for the tags that did not pass the threshold
+                let mut cells = vec![String::new(); self.labels.len()];
+                for category in TagCategory::all() {
+                    for (tag, prob) in result.get(&category) {
+                        if let Some(idx) = self.label2idx.get(tag) {
+                            cells[*idx] = prob.to_string();
+                        }
+                    }
+                }
+                csv.write_record(std::iter::once(image.display().to_string()).chain(cells))?;
+            }
+            #[cfg(feature = "parquet")]
+            (TableSink::Parquet(sink), _) => {
+                sink.write(long_rows(image, result, &self.label2idx))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the rows and close the table.
+    pub fn finish(&mut self) -> Result<()> {
+        match &mut self.sink {
+            TableSink::Csv(csv) => csv.flush()?,
+            #[cfg(feature = "parquet")]
+            TableSink::Parquet(sink) => sink.finish()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod columnar {
+    use anyhow::Result;
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch, StringArray};
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::LongRow;
+
+    /// Number of rows buffered before a row group is written
+    const ROW_GROUP_SIZE: usize = 65536;
+
+    /// Parquet file of the long table
+    pub struct ParquetSink {
+        writer: Option<ArrowWriter<File>>,
+        schema: Arc<Schema>,
+        rows: Vec<LongRow>,
+    }
+
+    impl ParquetSink {
+        pub fn new(path: &Path) -> Result<Self> {
+            let schema = Arc::new(Schema::new(vec![
+                Field::new("path", DataType::Utf8, false),
+                Field::new("tag", DataType::Utf8, false),
+                Field::new("category", DataType::Utf8, false),
+                Field::new("probability", DataType::Float32, false),
+            ]));
+            let writer = ArrowWriter::try_new(File::create(path)?, schema.clone(), None)?;
+
+            Ok(Self {
+                writer: Some(writer),
+                schema,
+                rows: vec![],
+            })
+        }
+
+        pub fn write(&mut self, rows: Vec<LongRow>) -> Result<()> {
+            self.rows.extend(rows);
+            if self.rows.len() >= ROW_GROUP_SIZE {
+                self.flush()?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            let Some(writer) = self.writer.as_mut() else {
+                return Ok(());
+            };
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+            let rows = std::mem::take(&mut self.rows);
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|row| row.path.as_str()),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|row| row.tag.as_str()),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|row| row.category),
+                )),
+                Arc::new(Float32Array::from_iter_values(
+                    rows.iter().map(|row| row.probability),
+                )),
+            ];
+            writer.write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+            Ok(())
+        }
+
+        pub fn finish(&mut self) -> Result<()> {
+            self.flush()?;
+            if let Some(writer) = self.writer.take() {
+                writer.close()?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::TempDir;
+    use indexmap::IndexMap;
+
+    /// Tags of the model in the order of the output
+    fn label_tags(temp: &TempDir) -> LabelTags {
+        let csv_path = temp.path().join("selected_tags.csv");
+        std::fs::write(
+            &csv_path,
+            "tag_id,name,category,count\n\
+             9999999,general,9,807690\n\
+             470575,1girl,0,4703758\n\
+             212816,solo,0,3966091\n\
+             1,hatsune_miku,4,100\n",
+        )
+        .unwrap();
+        LabelTags::load(&csv_path).unwrap()
+    }
+
+    fn tags() -> TaggingResult {
+        TaggingResult {
+            rating: IndexMap::from([("general".to_string(), 0.75)]),
+            character: IndexMap::from([("hatsune_miku".to_string(), 0.5)]),
+            // in descending order of probability, not in the order of the model
+            general: IndexMap::from([("solo".to_string(), 0.875), ("1girl".to_string(), 0.25)]),
+            artist: IndexMap::new(),
+            copyright: IndexMap::new(),
+            meta: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_long_rows() {
+        let temp = TempDir::new("long-rows");
+        let writer = TableWriter::csv(
+            Some(&temp.path().join("tags.csv")),
+            TableLayout::Long,
+            &label_tags(&temp),
+        )
+        .unwrap();
+
+        let rows = long_rows(Path::new("a.png"), &tags(), &writer.label2idx);
+        assert_eq!(
+            rows.iter()
+                .map(|row| (row.tag.as_str(), row.category, row.probability))
+                .collect::<Vec<_>>(),
+            vec![
+                ("general", "rating", 0.75),
+                ("1girl", "general", 0.25),
+                ("solo", "general", 0.875),
+                ("hatsune_miku", "character", 0.5),
+            ]
+        );
+        assert!(rows.iter().all(|row| row.path == "a.png"));
+    }
+
+    #[test]
+    fn test_csv_long() {
+        let temp = TempDir::new("csv-long");
+        let path = temp.path().join("tags.csv");
+        let mut writer =
+            TableWriter::csv(Some(&path), TableLayout::Long, &label_tags(&temp)).unwrap();
+        writer.write(Path::new("a.png"), &tags()).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "path,tag,category,probability\n\
+             a.png,general,rating,0.75\n\
+             a.png,1girl,general,0.25\n\
+             a.png,solo,general,0.875\n\
+             a.png,hatsune_miku,character,0.5\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_wide() {
+        let temp = TempDir::new("csv-wide");
+        let path = temp.path().join("tags.csv");
+        let mut writer =
+            TableWriter::csv(Some(&path), TableLayout::Wide, &label_tags(&temp)).unwrap();
+        writer.write(Path::new("a.png"), &tags()).unwrap();
+        let mut partial = tags();
+        partial.general.shift_remove("1girl");
+        partial.character.clear();
+        writer.write(Path::new("b.png"), &partial).unwrap();
+        writer.finish().unwrap();
+
+        // the tags that did not pass the threshold are empty cells
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "path,general,1girl,solo,hatsune_miku\n\
+             a.png,0.75,0.25,0.875,0.5\n\
+             b.png,0.75,,0.875,\n"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet() {
+        use arrow_schema::DataType;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let temp = TempDir::new("parquet");
+        let path = temp.path().join("tags.parquet");
+        let tags_of_model = label_tags(&temp);
+        assert!(TableWriter::parquet(Some(&path), TableLayout::Wide, &tags_of_model).is_err());
+
+        let mut writer =
+            TableWriter::parquet(Some(&path), TableLayout::Long, &tags_of_model).unwrap();
+        writer.write(Path::new("a.png"), &tags()).unwrap();
+        writer.finish().unwrap();
+
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+        let schema = reader.schema().clone();
+        assert_eq!(
+            schema
+                .fields()
+                .iter()
+                .map(|field| (
+                    field.name().as_str(),
+                    field.data_type().clone(),
+                    field.is_nullable()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("path", DataType::Utf8, false),
+                ("tag", DataType::Utf8, false),
+                ("category", DataType::Utf8, false),
+                ("probability", DataType::Float32, false),
+            ]
+        );
+        let rows = reader
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum::<usize>();
+        assert_eq!(rows, 4);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     error::TaggerError,
-    file::{ConfigFile, HfFile},
+    file::{ConfigFile, HfFile, HfOptions},
 };
 use serde::{Deserialize, Serialize};
 use std::{fs, path::Path};
@@ -18,6 +18,12 @@ pub struct PretrainedCfg {
     pub input_size: Vec<u32>, // [channels, height, width]
     pub fixed_input_size: bool,
     pub num_classes: u32,
+    /// Mean of each channel in RGB order for the normalization
+    #[serde(default)]
+    pub mean: Option<Vec<f32>>,
+    /// Standard deviation of each channel in RGB order for the normalization
+    #[serde(default)]
+    pub std: Option<Vec<f32>>,
 }
 
 impl ModelConfig {
@@ -29,7 +35,14 @@ impl ModelConfig {
     }
 
     pub fn from_pretrained(repo_id: &str) -> Result<Self, TaggerError> {
-        let config_file = ConfigFile::new(&repo_id).get()?;
+        Self::from_pretrained_with_options(repo_id, &HfOptions::from_env())
+    }
+
+    pub fn from_pretrained_with_options(
+        repo_id: &str,
+        options: &HfOptions,
+    ) -> Result<Self, TaggerError> {
+        let config_file = ConfigFile::new(repo_id).get_with_options(options)?;
         Self::load(config_file)
     }
 }
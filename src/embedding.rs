@@ -0,0 +1,354 @@
+use image::DynamicImage;
+use ndarray::{Array, Array2, ArrayD, Axis, Ix2, Ix4};
+use std::path::Path;
+
+use crate::config::ModelConfig;
+use crate::error::TaggerError;
+use crate::file::{ConfigFile, HfFile, HfOptions, TaggerModelFile};
+use crate::processor::{ImagePreprocessor, ImageProcessor};
+use crate::registry::LoadOptions;
+use crate::tagger::{Device, TaggerModel};
+
+/// Name of the output of the tag probabilities
+const PREDICTION_OUTPUT: &str = "output";
+
+/// File format of the embeddings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum EmbeddingFormat {
+    /// NumPy `.npy` file with the shape and the dtype
+    Npy,
+    /// Raw little-endian f32 values without a header
+    Raw,
+}
+
+impl EmbeddingFormat {
+    /// Extension of the files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EmbeddingFormat::Npy => "npy",
+            EmbeddingFormat::Raw => "f32",
+        }
+    }
+
+    /// Encode the values of the shape into the bytes of the file.
+    pub fn encode(&self, values: &[f32], shape: &[usize]) -> Vec<u8> {
+        match self {
+            EmbeddingFormat::Npy => encode_npy(values, shape),
+            EmbeddingFormat::Raw => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+}
+
+/// Encode the values into the NumPy format version 1.0 as a C-order little-endian f32 array.
+fn encode_npy(values: &[f32], shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [dim] => format!("({},)", dim),
+        dims => format!(
+            "({})",
+            dims.iter()
+                .map(|dim| dim.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // magic (6) + version (2) + header length (2) + header, padded to 64 bytes and ending with a newline
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    header.push_str(&" ".repeat(padding % 64));
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(10 + header.len() + values.len() * 4);
+    bytes.extend_from_slice(b"\x93NUMPY\x01\x00");
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+    bytes
+}
+
+/// Pool the features of the batch into a vector of `num_features` per image.
+///
+/// A `[batch, features]` output is kept as it is,
+/// the tokens of a `[batch, tokens, features]` output are averaged,
+/// and the spatial dimensions of a `[batch, features, height, width]`
+/// or `[batch, height, width, features]` output are averaged.
+pub fn pool(features: ArrayD<f32>, num_features: usize) -> Result<Array2<f32>, TaggerError> {
+    let shape = features.shape().to_vec();
+    let pooled = match shape.as_slice() {
+        [_, _] => Some(features),
+        [_, _, _] => features.mean_axis(Axis(1)),
+        [_, channels, _, _] if *channels == num_features => features
+            .mean_axis(Axis(3))
+            .and_then(|features| features.mean_axis(Axis(2))),
+        [_, _, _, _] => features
+            .mean_axis(Axis(2))
+            .and_then(|features| features.mean_axis(Axis(1))),
+        _ => None,
+    };
+
+    let pooled = pooled
+        .ok_or_else(|| {
+            TaggerError::Embedding(format!("Unexpected shape of the features: {:?}", shape))
+        })?
+        .into_dimensionality::<Ix2>()
+        .map_err(|e| TaggerError::Embedding(e.to_string()))?;
+    if pooled.shape()[1] != num_features {
+        return Err(TaggerError::Embedding(format!(
+            "Expected {} features, got the shape {:?}",
+            num_features, shape
+        )));
+    }
+    Ok(pooled)
+}
+
+/// Find the output of the features among the outputs of the model.
+///
+/// The requested output must exist. Otherwise the only output other than the tag probabilities is used.
+/// The tag probabilities are never taken as the features unless requested, so the released models
+/// fail here instead of on every image.
+pub fn find_feature_output(
+    outputs: &[String],
+    requested: Option<&str>,
+) -> Result<String, TaggerError> {
+    if let Some(requested) = requested {
+        return match outputs.iter().any(|output| output == requested) {
+            true => Ok(requested.to_string()),
+            false => Err(TaggerError::Embedding(format!(
+                "The model has no output named {}. The outputs are: {}",
+                requested,
+                outputs.join(", ")
+            ))),
+        };
+    }
+
+    let extra = outputs
+        .iter()
+        .filter(|output| *output != PREDICTION_OUTPUT)
+        .collect::<Vec<_>>();
+    match extra.as_slice() {
+        [output] => Ok(output.to_string()),
+        [] => Err(TaggerError::Embedding(format!(
+            "The model only outputs the tag probabilities ({}). Pass a model with the features \
+             as an output with --embedding-model, or the output of the features with --embedding-output",
+            outputs.join(", ")
+        ))),
+        _ => Err(TaggerError::Embedding(format!(
+            "Cannot tell which output has the features, pass one of {} with --embedding-output",
+            outputs.join(", ")
+        ))),
+    }
+}
+
+/// Pipeline that extracts the pooled features of the backbone, the input of the classifier head.
+///
+/// The features are read from an extra output of the model, or from a model whose graph is edited
+/// to return the features.
+#[derive(Debug)]
+pub struct EmbeddingPipeline {
+    pub model: TaggerModel,
+    pub preprocessor: ImagePreprocessor,
+    /// Name of the output of the features
+    output: String,
+    num_features: usize,
+}
+
+impl EmbeddingPipeline {
+    /// Create a new pipeline reading the features from the output.
+    /// Finds the output of the features if `output` is `None`.
+    pub fn new(
+        model: TaggerModel,
+        preprocessor: ImagePreprocessor,
+        output: Option<&str>,
+        num_features: usize,
+    ) -> Result<Self, TaggerError> {
+        let output = find_feature_output(&model.output_names(), output)?;
+        Ok(Self {
+            model,
+            preprocessor,
+            output,
+            num_features,
+        })
+    }
+
+    /// Load the pipeline from the model and config files.
+    pub fn load<P: AsRef<Path>>(
+        model_path: P,
+        config_path: P,
+        output: Option<&str>,
+        devices: Vec<Device>,
+    ) -> Result<Self, TaggerError> {
+        Self::load_with_options(
+            model_path,
+            config_path,
+            output,
+            devices,
+            &LoadOptions::default(),
+        )
+    }
+
+    /// Load the pipeline from the model and config files with the options of the model.
+    /// The name of the output in the options is not used.
+    pub fn load_with_options<P: AsRef<Path>>(
+        model_path: P,
+        config_path: P,
+        output: Option<&str>,
+        devices: Vec<Device>,
+        options: &LoadOptions,
+    ) -> Result<Self, TaggerError> {
+        let session = options.session.clone().or_devices(&devices);
+        let model =
+            TaggerModel::load_with_backend(model_path, &options.names, options.backend, &session)?;
+        let config = ModelConfig::load(config_path)?;
+        let preprocessor = ImagePreprocessor::detect(&config, model.input(), &options.preprocess)?;
+        model.validate_input(&preprocessor)?;
+
+        Self::new(model, preprocessor, output, config.num_features as usize)
+    }
+
+    /// Load the pipeline of the model on Hugging Face.
+    pub fn from_pretrained(
+        repo_id: &str,
+        output: Option<&str>,
+        devices: Vec<Device>,
+    ) -> Result<Self, TaggerError> {
+        Self::from_pretrained_with_options(repo_id, output, devices, &HfOptions::from_env())
+    }
+
+    /// Load the pipeline of the model on Hugging Face with the specified HuggingFace options.
+    pub fn from_pretrained_with_options(
+        repo_id: &str,
+        output: Option<&str>,
+        devices: Vec<Device>,
+        options: &HfOptions,
+    ) -> Result<Self, TaggerError> {
+        let model_path = TaggerModelFile::new(repo_id).get_with_options(options)?;
+        let config_path = ConfigFile::new(repo_id).get_with_options(options)?;
+
+        Self::load(model_path, config_path, output, devices)
+    }
+
+    /// Name of the output of the features
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Number of the features of an embedding
+    pub fn num_features(&self) -> usize {
+        self.num_features
+    }
+
+    /// Extract the embedding of an image.
+    pub fn predict(&self, image: DynamicImage) -> Result<Vec<f32>, TaggerError> {
+        let tensor = self.preprocessor.process(&image)?;
+        let mut embeddings = self.predict_tensor(tensor)?;
+        Ok(embeddings.remove(0))
+    }
+
+    /// Extract the embeddings of a batch of images.
+    pub fn predict_batch(&self, images: Vec<DynamicImage>) -> Result<Vec<Vec<f32>>, TaggerError> {
+        let tensor = self.preprocessor.process_batch(images)?;
+        self.predict_tensor(tensor)
+    }
+
+    /// Extract the embeddings of the preprocessed batch.
+    pub fn predict_tensor(&self, tensor: Array<f32, Ix4>) -> Result<Vec<Vec<f32>>, TaggerError> {
+        let features = self.model.predict_output(tensor, &self.output)?;
+        let pooled = pool(features, self.num_features)?;
+
+        Ok(pooled.axis_iter(Axis(0)).map(|row| row.to_vec()).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::IxDyn;
+
+    #[test]
+    fn test_pool() {
+        let features = ArrayD::from_elem(IxDyn(&[2, 8]), 1.0);
+        assert_eq!(pool(features, 8).unwrap().shape(), &[2, 8]);
+
+        // tokens
+        let features = Array::from_shape_fn((2, 3, 8), |(_, token, _)| token as f32).into_dyn();
+        let pooled = pool(features, 8).unwrap();
+        assert_eq!(pooled.shape(), &[2, 8]);
+        assert_eq!(pooled[[1, 0]], 1.0);
+
+        // channels first
+        let features = Array::from_shape_fn((2, 8, 2, 2), |(_, _, h, w)| (h + w) as f32).into_dyn();
+        let pooled = pool(features, 8).unwrap();
+        assert_eq!(pooled.shape(), &[2, 8]);
+        assert_eq!(pooled[[0, 3]], 1.0);
+
+        // channels last
+        let features = Array::from_shape_fn((2, 3, 3, 8), |(_, h, _, _)| h as f32).into_dyn();
+        let pooled = pool(features, 8).unwrap();
+        assert_eq!(pooled.shape(), &[2, 8]);
+        assert_eq!(pooled[[0, 7]], 1.0);
+
+        // tag probabilities instead of the features
+        let features = ArrayD::from_elem(IxDyn(&[2, 10]), 1.0);
+        assert!(pool(features, 8).is_err());
+    }
+
+    #[test]
+    fn test_find_feature_output() {
+        let outputs = vec!["output".to_string(), "features".to_string()];
+        assert_eq!(find_feature_output(&outputs, None).unwrap(), "features");
+        assert_eq!(
+            find_feature_output(&outputs, Some("output")).unwrap(),
+            "output"
+        );
+        assert!(find_feature_output(&outputs, Some("unknown")).is_err());
+
+        // graph-edited model
+        let outputs = vec!["features".to_string()];
+        assert_eq!(find_feature_output(&outputs, None).unwrap(), "features");
+
+        // released model with only the tag probabilities
+        let outputs = vec!["output".to_string()];
+        assert!(find_feature_output(&outputs, None).is_err());
+        assert_eq!(
+            find_feature_output(&outputs, Some("output")).unwrap(),
+            "output"
+        );
+
+        let outputs = vec!["output".to_string(), "a".to_string(), "b".to_string()];
+        assert!(find_feature_output(&outputs, None).is_err());
+    }
+
+    #[test]
+    fn test_encode_npy() {
+        let bytes = EmbeddingFormat::Npy.encode(&[1.0, 2.0, 3.0], &[3]);
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.contains("'descr': '<f4'"));
+        assert!(header.contains("'shape': (3,)"));
+        assert!(header.ends_with('\n'));
+
+        assert_eq!(bytes.len(), 10 + header_len + 12);
+        assert_eq!(
+            &bytes[10 + header_len..10 + header_len + 4],
+            &1.0f32.to_le_bytes()
+        );
+
+        let bytes = EmbeddingFormat::Npy.encode(&[0.0; 6], &[2, 3]);
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (2, 3)"));
+    }
+
+    #[test]
+    fn test_encode_raw() {
+        let bytes = EmbeddingFormat::Raw.encode(&[1.0, -2.5], &[2]);
+        assert_eq!(bytes.len(), 8);
+        assert_eq!(&bytes[4..], &(-2.5f32).to_le_bytes());
+    }
+}
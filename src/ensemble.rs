@@ -0,0 +1,238 @@
+use image::DynamicImage;
+use std::collections::HashMap;
+
+use crate::error::TaggerError;
+use crate::file::HfOptions;
+use crate::pipeline::{threshold_tags, TaggingPipeline, TaggingResult};
+use crate::processor::ImageProcessor;
+use crate::tagger::Device;
+use crate::tags::{Tag, TagCategory};
+use crate::threshold::Threshold;
+
+/// How to combine the probabilities of the models
+#[derive(Debug, Clone, PartialEq)]
+pub enum Combination {
+    /// Mean of the probabilities
+    Mean,
+    /// Maximum of the probabilities
+    Max,
+    /// Weighted vote with a weight per model, in the order of the models
+    Weighted(Vec<f32>),
+}
+
+/// Combine the tag probabilities of the models.
+/// A tag that some models do not know is combined over the models that know it.
+pub fn combine(probs: &[&HashMap<String, f32>], combination: &Combination) -> HashMap<String, f32> {
+    // (weighted sum or max, sum of the weights) of each tag
+    let mut combined: HashMap<String, (f32, f32)> = HashMap::new();
+
+    for (idx, probs) in probs.iter().enumerate() {
+        let weight = match combination {
+            Combination::Weighted(weights) => weights[idx],
+            _ => 1.0,
+        };
+        for (tag, prob) in probs.iter() {
+            let (value, weights) = combined.entry(tag.clone()).or_insert((0.0, 0.0));
+            match combination {
+                Combination::Max => *value = value.max(*prob),
+                _ => *value += prob * weight,
+            }
+            *weights += weight;
+        }
+    }
+
+    combined
+        .into_iter()
+        .map(|(tag, (value, weights))| {
+            let prob = match combination {
+                Combination::Max => value,
+                _ if weights > 0.0 => value / weights,
+                _ => 0.0,
+            };
+            (tag, prob)
+        })
+        .collect()
+}
+
+/// Pipeline that tags images with several models and combines their outputs by tag name.
+///
+/// Each model preprocesses the images with its own input size,
+/// and the threshold is applied to the combined probabilities.
+#[derive(Debug)]
+pub struct EnsemblePipeline {
+    pipelines: Vec<TaggingPipeline>,
+    combination: Combination,
+    threshold: Threshold,
+    /// Tags of all the models by name
+    label2tag: HashMap<String, Tag>,
+}
+
+impl EnsemblePipeline {
+    /// Create a new ensemble of the pipelines. The thresholds of the pipelines are not used.
+    pub fn new(
+        pipelines: Vec<TaggingPipeline>,
+        combination: Combination,
+    ) -> Result<Self, TaggerError> {
+        if pipelines.is_empty() {
+            return Err(TaggerError::Ensemble(
+                "No model in the ensemble".to_string(),
+            ));
+        }
+        if let Combination::Weighted(weights) = &combination {
+            if weights.len() != pipelines.len() {
+                return Err(TaggerError::Ensemble(format!(
+                    "{} weights for {} models",
+                    weights.len(),
+                    pipelines.len()
+                )));
+            }
+            if weights.iter().any(|weight| *weight < 0.0) {
+                return Err(TaggerError::Ensemble(
+                    "Weights must not be negative".to_string(),
+                ));
+            }
+        }
+
+        let mut label2tag = HashMap::new();
+        for pipeline in &pipelines {
+            for (label, tag) in pipeline.tags.label2tag() {
+                label2tag
+                    .entry(label.clone())
+                    .or_insert_with(|| tag.clone());
+            }
+        }
+
+        Ok(Self {
+            pipelines,
+            combination,
+            threshold: Threshold::default(),
+            label2tag,
+        })
+    }
+
+    /// Create a new ensemble of the models on Hugging Face.
+    pub fn from_pretrained(
+        repo_ids: &[&str],
+        devices: Vec<Device>,
+        combination: Combination,
+    ) -> Result<Self, TaggerError> {
+        Self::from_pretrained_with_options(repo_ids, devices, combination, &HfOptions::from_env())
+    }
+
+    /// Create a new ensemble of the models on Hugging Face with the specified HuggingFace options.
+    pub fn from_pretrained_with_options(
+        repo_ids: &[&str],
+        devices: Vec<Device>,
+        combination: Combination,
+        options: &HfOptions,
+    ) -> Result<Self, TaggerError> {
+        let pipelines = repo_ids
+            .iter()
+            .map(|repo_id| {
+                TaggingPipeline::from_pretrained_with_options(repo_id, devices.clone(), options)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(pipelines, combination)
+    }
+
+    /// Set the thresholding strategy of the combined probabilities.
+    pub fn with_threshold(mut self, threshold: Threshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Set the threshold for the category, keeping the thresholds of the other categories.
+    pub fn with_category_threshold(mut self, category: TagCategory, threshold: Threshold) -> Self {
+        self.threshold = self.threshold.with_category(category, threshold);
+        self
+    }
+
+    /// Get the thresholding strategy.
+    pub fn threshold(&self) -> &Threshold {
+        &self.threshold
+    }
+
+    /// Get the pipelines of the models.
+    pub fn pipelines(&self) -> &[TaggingPipeline] {
+        &self.pipelines
+    }
+
+    /// Predict the tags of an image.
+    pub fn predict(&self, image: DynamicImage) -> Result<TaggingResult, TaggerError> {
+        let mut results = self.predict_batch(vec![image])?;
+        Ok(results.remove(0))
+    }
+
+    /// Predict the tags of a batch of images.
+    pub fn predict_batch(
+        &self,
+        images: Vec<DynamicImage>,
+    ) -> Result<Vec<TaggingResult>, TaggerError> {
+        // probabilities of each model of each image
+        let probs = self
+            .pipelines
+            .iter()
+            .map(|pipeline| {
+                let tensor = pipeline.preprocessor.process_batch(images.clone())?;
+                pipeline.predict_probabilities(tensor)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let results = (0..images.len())
+            .map(|idx| {
+                let image_probs = probs.iter().map(|probs| &probs[idx]).collect::<Vec<_>>();
+                let combined = combine(&image_probs, &self.combination);
+                threshold_tags(&combined, &self.label2tag, &self.threshold)
+            })
+            .collect();
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn probs(pairs: &[(&str, f32)]) -> HashMap<String, f32> {
+        pairs
+            .iter()
+            .map(|(tag, prob)| (tag.to_string(), *prob))
+            .collect()
+    }
+
+    #[test]
+    fn test_combine() {
+        let a = probs(&[("1girl", 0.9), ("solo", 0.4), ("smile", 0.2)]);
+        let b = probs(&[("1girl", 0.7), ("solo", 0.8)]);
+
+        let mean = combine(&[&a, &b], &Combination::Mean);
+        assert!((mean["1girl"] - 0.8).abs() < 1e-6);
+        assert!((mean["solo"] - 0.6).abs() < 1e-6);
+        // only the first model knows the tag
+        assert!((mean["smile"] - 0.2).abs() < 1e-6);
+
+        let max = combine(&[&a, &b], &Combination::Max);
+        assert_eq!(max["1girl"], 0.9);
+        assert_eq!(max["solo"], 0.8);
+
+        let weighted = combine(&[&a, &b], &Combination::Weighted(vec![3.0, 1.0]));
+        assert!((weighted["1girl"] - 0.85).abs() < 1e-6);
+        assert!((weighted["solo"] - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_combine_zero_weight() {
+        let a = probs(&[("1girl", 0.9)]);
+        let b = probs(&[("solo", 0.8)]);
+
+        let weighted = combine(&[&a, &b], &Combination::Weighted(vec![1.0, 0.0]));
+        assert!((weighted["1girl"] - 0.9).abs() < 1e-6);
+        assert_eq!(weighted["solo"], 0.0);
+    }
+
+    #[test]
+    fn test_invalid_ensemble() {
+        assert!(EnsemblePipeline::new(vec![], Combination::Mean).is_err());
+    }
+}
@@ -14,6 +14,14 @@ pub enum TaggerError {
     Tag(String),
     /// Error around I/O
     Io(String),
+    /// Error around the model registry
+    Registry(String),
+    /// Error around the ensemble of the models
+    Ensemble(String),
+    /// Error around the embedding extraction
+    Embedding(String),
+    /// Error around the inference backend
+    Backend(String),
 }
 
 impl Display for TaggerError {
@@ -25,6 +33,10 @@ impl Display for TaggerError {
             TaggerError::Processor(message) => write!(f, "Processor Error: {}", message),
             TaggerError::Tag(message) => write!(f, "Tag Error: {}", message),
             TaggerError::Io(e) => write!(f, "I/O Error: {}", e),
+            TaggerError::Registry(message) => write!(f, "Registry Error: {}", message),
+            TaggerError::Ensemble(message) => write!(f, "Ensemble Error: {}", message),
+            TaggerError::Embedding(message) => write!(f, "Embedding Error: {}", message),
+            TaggerError::Backend(message) => write!(f, "Backend Error: {}", message),
         }
     }
 }
@@ -4,7 +4,93 @@ use hf_hub::{
     api::sync::{Api, ApiBuilder, ApiRepo},
     Cache, Repo, RepoType,
 };
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Options to access the HuggingFace Hub
+#[derive(Debug, Clone, Default)]
+pub struct HfOptions {
+    /// Endpoint URL of the Hub. `https://huggingface.co` if `None`.
+    pub endpoint: Option<String>,
+    /// Access token. The token saved by `huggingface-cli login` is used if `None`.
+    pub token: Option<String>,
+    /// Hub cache directory. `$HF_HOME/hub` or `~/.cache/huggingface/hub` if `None`.
+    pub cache_dir: Option<PathBuf>,
+    /// Use only the cache without network access
+    pub offline: bool,
+    /// Revision (branch, tag or commit hash) of the repo.
+    /// The revision of each file takes precedence if specified.
+    pub revision: Option<String>,
+}
+
+impl HfOptions {
+    /// Read the options from `HF_ENDPOINT`, `HF_TOKEN`, `HF_HUB_CACHE` and `HF_HUB_OFFLINE`.
+    pub fn from_env() -> Self {
+        let var = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+
+        Self {
+            endpoint: var("HF_ENDPOINT"),
+            token: var("HF_TOKEN"),
+            cache_dir: var("HF_HUB_CACHE").map(PathBuf::from),
+            offline: var("HF_HUB_OFFLINE")
+                .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false),
+            revision: None,
+        }
+    }
+
+    /// Set the endpoint URL
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    /// Set the access token
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Set the hub cache directory
+    pub fn with_cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache_dir = Some(cache_dir);
+        self
+    }
+
+    /// Set the offline mode
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Set the revision of the repo
+    pub fn with_revision(mut self, revision: &str) -> Self {
+        self.revision = Some(revision.to_string());
+        self
+    }
+
+    /// Get the cache
+    pub fn cache(&self) -> Cache {
+        match &self.cache_dir {
+            Some(cache_dir) => Cache::new(cache_dir.clone()),
+            None => Cache::from_env(),
+        }
+    }
+
+    /// Build the API client
+    fn api(&self) -> Result<Api, TaggerError> {
+        let mut builder = ApiBuilder::from_cache(self.cache());
+        if let Some(endpoint) = &self.endpoint {
+            builder = builder.with_endpoint(endpoint.clone());
+        }
+        if let Some(token) = &self.token {
+            builder = builder.with_token(Some(token.clone()));
+        }
+
+        builder
+            .build()
+            .map_err(|e| TaggerError::Hf(format!("Error while building API: {}", e)))
+    }
+}
 
 /// Trait for the HuggingFace file
 pub trait HfFile {
@@ -20,15 +106,11 @@ pub trait HfFile {
     /// Get the model_path
     fn file_path(&self) -> String;
 
-    /// Get repo object
-    fn _api_repo(&self, api: Api) -> ApiRepo {
-        match self.revision() {
-            Some(revision) => api.repo(Repo::with_revision(
-                self.repo_id(),
-                RepoType::Model,
-                revision,
-            )),
-            None => api.repo(Repo::new(self.repo_id(), RepoType::Model)),
+    /// Get repo, using the revision of the options if the file has none
+    fn _repo(&self, options: &HfOptions) -> Repo {
+        match self.revision().or(options.revision.clone()) {
+            Some(revision) => Repo::with_revision(self.repo_id(), RepoType::Model, revision),
+            None => Repo::new(self.repo_id(), RepoType::Model),
         }
     }
 
@@ -40,9 +122,20 @@ pub trait HfFile {
         }
     }
 
-    /// Download or use cache using default cache config and return the file path
+    /// Download or use cache using the options from the environment variables and return the file path
     fn get(&self) -> Result<PathBuf, TaggerError> {
-        self.get_with_cache(Cache::default())
+        self.get_with_options(&HfOptions::from_env())
+    }
+
+    /// Download or use cache using specified options and return the file path
+    fn get_with_options(&self, options: &HfOptions) -> Result<PathBuf, TaggerError> {
+        if options.offline {
+            return self._get_cached_file(options.cache(), options);
+        }
+
+        let repo = options.api()?.repo(self._repo(options));
+
+        self._get_file(repo, &self.file_path())
     }
 
     /// Download or use cache using specified cache config and return the file path
@@ -53,10 +146,46 @@ pub trait HfFile {
             Err(e) => return Err(TaggerError::Hf(format!("Error while building API: {}", e))),
         };
 
-        let repo = self._api_repo(api);
+        let repo = api.repo(self._repo(&HfOptions::default()));
 
         self._get_file(repo, &self.file_path())
     }
+
+    /// Use only the cache without network access and return the file path
+    fn get_offline(&self) -> Result<PathBuf, TaggerError> {
+        self.get_offline_with_cache(HfOptions::from_env().cache())
+    }
+
+    /// Use only the specified cache without network access and return the file path
+    fn get_offline_with_cache(&self, cache: Cache) -> Result<PathBuf, TaggerError> {
+        self._get_cached_file(cache, &HfOptions::default())
+    }
+
+    /// Get file from the cache
+    fn _get_cached_file(&self, cache: Cache, options: &HfOptions) -> Result<PathBuf, TaggerError> {
+        let repo = self._repo(options);
+        let revision = repo.revision().to_string();
+
+        cache.repo(repo).get(&self.file_path()).ok_or_else(|| {
+            TaggerError::Hf(format!(
+                "{} of {} (revision: {}) is not found in the cache {} while offline",
+                self.file_path(),
+                self.repo_id(),
+                revision,
+                cache.path().display()
+            ))
+        })
+    }
+}
+
+/// Get the commit hash that the cached file was resolved to,
+/// from its path `.../snapshots/<commit_hash>/<file>`.
+pub fn resolved_revision<P: AsRef<Path>>(path: P) -> Option<String> {
+    let snapshot = path.as_ref().parent()?;
+    match snapshot.parent()?.file_name()? == "snapshots" {
+        true => Some(snapshot.file_name()?.to_string_lossy().to_string()),
+        false => None,
+    }
 }
 
 /// Model for the Tagging
@@ -176,9 +305,37 @@ impl HfFile for ConfigFile {
     }
 }
 
+/// Empty folder for the files of a test, unique to the test and the process,
+/// and removed when dropped
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("wdtagger-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
 
     #[test]
     fn test_get_model() {
@@ -243,6 +400,132 @@ mod test {
         assert!(config_file_custom.get().is_ok());
     }
 
+    #[test]
+    fn test_get_offline() {
+        let temp = TempDir::new("get-offline");
+        let cache_dir = temp.path().to_path_buf();
+        let snapshot = cache_dir.join("models--Org--tagger/snapshots/abc123");
+        std::fs::create_dir_all(&snapshot).unwrap();
+        std::fs::create_dir_all(cache_dir.join("models--Org--tagger/refs")).unwrap();
+        std::fs::write(cache_dir.join("models--Org--tagger/refs/main"), "abc123").unwrap();
+        std::fs::write(snapshot.join("config.json"), "{}").unwrap();
+
+        let cache = Cache::new(cache_dir);
+
+        let path = ConfigFile::new("Org/tagger")
+            .get_offline_with_cache(cache.clone())
+            .unwrap();
+        assert_eq!(path, snapshot.join("config.json"));
+
+        // cache miss
+        assert!(TaggerModelFile::new("Org/tagger")
+            .get_offline_with_cache(cache.clone())
+            .is_err());
+        assert!(
+            ConfigFile::custom("Org/tagger", Some("v1".to_string()), "config.json")
+                .get_offline_with_cache(cache)
+                .is_err()
+        );
+
+        assert_eq!(resolved_revision(&path), Some("abc123".to_string()));
+    }
+
+    #[test]
+    fn test_get_offline_with_revision() {
+        let temp = TempDir::new("get-offline-with-revision");
+        let cache_dir = temp.path().to_path_buf();
+        let snapshot = cache_dir.join("models--Org--tagger/snapshots/def456");
+        std::fs::create_dir_all(&snapshot).unwrap();
+        std::fs::create_dir_all(cache_dir.join("models--Org--tagger/refs")).unwrap();
+        std::fs::write(cache_dir.join("models--Org--tagger/refs/v1"), "def456").unwrap();
+        std::fs::write(snapshot.join("config.json"), "{}").unwrap();
+
+        let options = HfOptions::default()
+            .with_cache_dir(cache_dir)
+            .with_offline(true);
+
+        // main is not cached
+        assert!(ConfigFile::new("Org/tagger")
+            .get_with_options(&options)
+            .is_err());
+
+        let path = ConfigFile::new("Org/tagger")
+            .get_with_options(&options.clone().with_revision("v1"))
+            .unwrap();
+        assert_eq!(resolved_revision(path), Some("def456".to_string()));
+
+        // the revision of the file takes precedence
+        assert!(
+            ConfigFile::custom("Org/tagger", Some("main".to_string()), "config.json")
+                .get_with_options(&options.with_revision("v1"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_get_with_options_mock_server() {
+        // HF compatible server that serves `{}` for any file
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            // metadata and download
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\n\
+                          etag: \"abc\"\r\n\
+                          x-repo-commit: 0123\r\n\
+                          content-range: bytes 0-1/2\r\n\
+                          content-length: 2\r\n\
+                          connection: close\r\n\r\n{}",
+                    )
+                    .unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let temp = TempDir::new("get-with-options");
+        let cache_dir = temp.path().to_path_buf();
+        let options = HfOptions::default()
+            .with_endpoint(&endpoint)
+            .with_token("secret")
+            .with_cache_dir(cache_dir.clone());
+
+        let path = ConfigFile::new("Org/tagger")
+            .get_with_options(&options)
+            .unwrap();
+        assert!(path.starts_with(&cache_dir));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /Org/tagger/resolve/main/config.json"));
+        assert!(requests.iter().all(|request| request
+            .to_lowercase()
+            .contains("authorization: bearer secret")));
+
+        // now it is cached
+        let offline = options.with_offline(true);
+        assert_eq!(
+            ConfigFile::new("Org/tagger")
+                .get_with_options(&offline)
+                .unwrap(),
+            path
+        );
+    }
+
     #[test]
     fn test_get_config_many() {
         let repo_ids = vec![
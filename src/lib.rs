@@ -1,7 +1,14 @@
+pub mod backend;
+pub mod batch;
 pub mod config;
+pub mod embedding;
+pub mod ensemble;
 pub mod error;
 pub mod file;
 pub mod pipeline;
+pub mod preset;
 pub mod processor;
+pub mod registry;
 pub mod tagger;
 pub mod tags;
+pub mod threshold;
@@ -2,10 +2,19 @@ use anyhow::Result;
 use image::DynamicImage;
 use indexmap::IndexMap;
 use itertools::Itertools;
+use ndarray::{Array, Ix4};
+use serde::{Deserialize, Serialize};
 
-use crate::processor::{ImagePreprocessor, ImageProcessor};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::backend::SessionOptions;
+use crate::batch::AdaptiveBatcher;
+use crate::file::HfOptions;
+use crate::processor::{ImagePreprocessor, ImageProcessor, PreprocessOptions};
 use crate::tagger::Device;
-use crate::tags::{LabelTags, TagCategory};
+use crate::tags::{LabelTags, Tag, TagCategory};
+use crate::threshold::Threshold;
 use crate::{config::ModelConfig, error::TaggerError, tagger::TaggerModel};
 
 /// Pipeline for tagging images.
@@ -14,7 +23,7 @@ pub struct TaggingPipeline {
     pub model: TaggerModel,
     pub preprocessor: ImagePreprocessor,
     pub tags: LabelTags,
-    threshold: f32,
+    threshold: Threshold,
 }
 
 // type alias for prediction result
@@ -27,7 +36,8 @@ fn sort_by_value(map: &Prediction) -> Prediction {
         .collect()
 }
 
-#[derive(Debug, Clone)]
+/// Tags of each category in descending order of probability
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TaggingResult {
     /// Rating tags
     pub rating: Prediction,
@@ -35,14 +45,36 @@ pub struct TaggingResult {
     pub character: Prediction,
     /// General tags
     pub general: Prediction,
+    /// Artist tags
+    pub artist: Prediction,
+    /// Copyright tags
+    pub copyright: Prediction,
+    /// Meta tags
+    pub meta: Prediction,
 }
 
 impl TaggingResult {
-    fn new(rating: &Prediction, character: &Prediction, general: &Prediction) -> Self {
+    /// Create a result with the tags of each category given by `tags`.
+    fn new<F: Fn(TagCategory) -> Prediction>(tags: F) -> Self {
         Self {
-            rating: sort_by_value(&rating),
-            character: sort_by_value(&character),
-            general: sort_by_value(&general),
+            rating: sort_by_value(&tags(TagCategory::Rating)),
+            character: sort_by_value(&tags(TagCategory::Character)),
+            general: sort_by_value(&tags(TagCategory::General)),
+            artist: sort_by_value(&tags(TagCategory::Artist)),
+            copyright: sort_by_value(&tags(TagCategory::Copyright)),
+            meta: sort_by_value(&tags(TagCategory::Meta)),
+        }
+    }
+
+    /// Get the tags of the category.
+    pub fn get(&self, category: &TagCategory) -> &Prediction {
+        match category {
+            TagCategory::Rating => &self.rating,
+            TagCategory::Character => &self.character,
+            TagCategory::General => &self.general,
+            TagCategory::Artist => &self.artist,
+            TagCategory::Copyright => &self.copyright,
+            TagCategory::Meta => &self.meta,
         }
     }
 }
@@ -53,30 +85,99 @@ impl TaggingPipeline {
         model: TaggerModel,
         preprocessor: ImagePreprocessor,
         tags: LabelTags,
-        threshold: &f32,
+        threshold: Threshold,
     ) -> Self {
         Self {
             model,
             preprocessor,
             tags,
-            threshold: *threshold,
+            threshold,
         }
     }
 
+    /// Set the thresholding strategy.
+    pub fn with_threshold(mut self, threshold: Threshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Set the threshold for the general tags.
+    pub fn with_general_threshold(self, threshold: Threshold) -> Self {
+        self.with_category_threshold(TagCategory::General, threshold)
+    }
+
+    /// Set the threshold for the character tags.
+    pub fn with_character_threshold(self, threshold: Threshold) -> Self {
+        self.with_category_threshold(TagCategory::Character, threshold)
+    }
+
+    /// Set the threshold for the category, keeping the thresholds of the other categories.
+    pub fn with_category_threshold(mut self, category: TagCategory, threshold: Threshold) -> Self {
+        self.threshold = self.threshold.with_category(category, threshold);
+        self
+    }
+
+    /// Get the thresholding strategy.
+    pub fn threshold(&self) -> &Threshold {
+        &self.threshold
+    }
+
     /// Create a new tagging pipeline.
     pub fn from_pretrained(model_name: &str, devices: Vec<Device>) -> Result<Self, TaggerError> {
-        TaggerModel::use_devices(devices)?;
+        Self::from_pretrained_with_options(model_name, devices, &HfOptions::from_env())
+    }
 
-        let model = TaggerModel::from_pretrained(&model_name)?;
-        let config = ModelConfig::from_pretrained(&model_name)?;
-        let preprocessor = ImagePreprocessor::from_config(&config)?;
-        let tags = LabelTags::from_pretrained(model_name)?;
+    /// Create a new tagging pipeline with the specified HuggingFace options.
+    pub fn from_pretrained_with_options(
+        model_name: &str,
+        devices: Vec<Device>,
+        options: &HfOptions,
+    ) -> Result<Self, TaggerError> {
+        let session = SessionOptions::default().with_devices(devices);
+        let model = TaggerModel::from_pretrained_with_session(model_name, options, &session)?;
+        let config = ModelConfig::from_pretrained_with_options(model_name, options)?;
+        let preprocessor =
+            ImagePreprocessor::detect(&config, model.input(), &PreprocessOptions::default())?;
+        model.validate(&config, &preprocessor)?;
+        let tags = LabelTags::from_pretrained_with_options(model_name, options)?;
 
         Ok(Self {
             model,
             preprocessor,
             tags,
-            threshold: 0.35,
+            threshold: Threshold::default(),
+        })
+    }
+
+    /// Create a new tagging pipeline from a local folder
+    /// that has `model.onnx`, `config.json` and `selected_tags.csv`.
+    pub fn from_dir<P: AsRef<Path>>(dir: P, devices: Vec<Device>) -> Result<Self, TaggerError> {
+        let dir = dir.as_ref();
+        let path = |filename: &str| {
+            let path = dir.join(filename);
+            match path.is_file() {
+                true => Ok(path),
+                false => Err(TaggerError::Io(format!(
+                    "{} is not found in {}",
+                    filename,
+                    dir.display()
+                ))),
+            }
+        };
+
+        let session = SessionOptions::default().with_devices(devices);
+        let model = TaggerModel::load_with_session(path("model.onnx")?, &session)?;
+        let config = ModelConfig::load(path("config.json")?)?;
+        let preprocessor =
+            ImagePreprocessor::detect(&config, model.input(), &PreprocessOptions::default())?;
+        model.validate(&config, &preprocessor)?;
+        let tags = LabelTags::load(path("selected_tags.csv")?)?;
+
+        Ok(Self {
+            model,
+            preprocessor,
+            tags,
+            threshold: Threshold::default(),
         })
     }
 
@@ -85,25 +186,9 @@ impl TaggingPipeline {
         let tensor = self.preprocessor.process(&image)?;
         let probs = self.model.predict(tensor)?;
         let pairs = self.tags.create_probality_pairs(probs)?;
-        let pairs = pairs.first().unwrap().clone();
-
-        macro_rules! filter_tags {
-            ($category:expr) => {
-                pairs
-                    .iter()
-                    .filter(|(tag, &prob)| {
-                        self.tags.label2tag().get(tag.clone()).unwrap().category() == $category
-                            && &prob >= &self.threshold
-                    })
-                    .map(|(tag, prob)| (tag.clone(), *prob))
-                    .collect::<Prediction>()
-            };
-        }
-        let rating: Prediction = filter_tags!(TagCategory::Rating);
-        let character: Prediction = filter_tags!(TagCategory::Character);
-        let general: Prediction = filter_tags!(TagCategory::General);
+        let pairs = pairs.first().unwrap();
 
-        Ok(TaggingResult::new(&rating, &character, &general))
+        Ok(self.postprocess(pairs))
     }
 
     /// Predict the tags of a batch of images.
@@ -112,35 +197,91 @@ impl TaggingPipeline {
         images: Vec<DynamicImage>,
     ) -> Result<Vec<TaggingResult>, TaggerError> {
         let tensor = self.preprocessor.process_batch(images)?;
-        let probs = self.model.predict(tensor)?;
-        let pairs = self.tags.create_probality_pairs(probs)?;
+        self.predict_tensor(tensor)
+    }
+
+    /// Predict the tags of a batch of images in the batches that fit in the memory,
+    /// and get the result, or the error, of each image.
+    pub fn predict_batch_adaptive(
+        &self,
+        images: Vec<DynamicImage>,
+        batcher: &AdaptiveBatcher,
+    ) -> Result<Vec<Result<TaggingResult, TaggerError>>, TaggerError> {
+        let tensor = self.preprocessor.process_batch(images)?;
+        Ok(batcher.run(tensor, |tensor| self.predict_tensor(tensor)))
+    }
+
+    /// Predict the tags of a batch of images already preprocessed into a tensor.
+    pub fn predict_tensor(
+        &self,
+        tensor: Array<f32, Ix4>,
+    ) -> Result<Vec<TaggingResult>, TaggerError> {
+        let pairs = self.predict_probabilities(tensor)?;
 
         let results = pairs
             .iter()
-            .map(|pairs| {
-                macro_rules! filter_tags {
-                    ($category:expr) => {
-                        pairs
-                            .iter()
-                            .filter(|(tag, &prob)| {
-                                self.tags.label2tag().get(tag.clone()).unwrap().category()
-                                    == $category
-                                    && &prob >= &self.threshold
-                            })
-                            .map(|(tag, prob)| (tag.clone(), *prob))
-                            .collect::<Prediction>()
-                    };
-                }
-                let rating: Prediction = filter_tags!(TagCategory::Rating);
-                let character: Prediction = filter_tags!(TagCategory::Character);
-                let general: Prediction = filter_tags!(TagCategory::General);
-
-                TaggingResult::new(&rating, &character, &general)
-            })
+            .map(|pairs| self.postprocess(pairs))
             .collect::<Vec<TaggingResult>>();
 
         Ok(results)
     }
+
+    /// Predict the probability of every tag of a batch of images already preprocessed into a tensor,
+    /// without applying the threshold.
+    pub fn predict_probabilities(
+        &self,
+        tensor: Array<f32, Ix4>,
+    ) -> Result<Vec<HashMap<String, f32>>, TaggerError> {
+        let probs = self.model.predict(tensor)?;
+        self.tags.create_probality_pairs(probs)
+    }
+
+    /// Split the tag probabilities into categories and apply the threshold.
+    fn postprocess(&self, pairs: &HashMap<String, f32>) -> TaggingResult {
+        self.postprocess_with_threshold(pairs, &self.threshold)
+    }
+
+    /// Split the tag probabilities into categories and apply the specified threshold
+    /// instead of the one of the pipeline.
+    pub fn postprocess_with_threshold(
+        &self,
+        pairs: &HashMap<String, f32>,
+        threshold: &Threshold,
+    ) -> TaggingResult {
+        threshold_tags(pairs, self.tags.label2tag(), threshold)
+    }
+}
+
+/// Split the tag probabilities into categories by `label2tag` and apply the threshold.
+pub(crate) fn threshold_tags(
+    pairs: &HashMap<String, f32>,
+    label2tag: &HashMap<String, Tag>,
+    threshold: &Threshold,
+) -> TaggingResult {
+    TaggingResult::new(|category| filter_tags(pairs, label2tag, category, threshold))
+}
+
+/// Get the tags of the category that pass the threshold.
+fn filter_tags(
+    pairs: &HashMap<String, f32>,
+    label2tag: &HashMap<String, Tag>,
+    category: TagCategory,
+    threshold: &Threshold,
+) -> Prediction {
+    let tags = pairs
+        .iter()
+        .filter(|(tag, _)| label2tag.get(*tag).unwrap().category() == category)
+        .map(|(tag, prob)| (tag.clone(), *prob))
+        .collect::<Prediction>();
+
+    let probs = tags.values().copied().collect::<Vec<_>>();
+    match threshold.compute(&category, &probs) {
+        Some(threshold) => tags
+            .into_iter()
+            .filter(|(_, prob)| *prob >= threshold)
+            .collect(),
+        None => tags,
+    }
 }
 
 #[cfg(test)]
@@ -202,4 +343,25 @@ mod test {
             .collect::<IndexMap<_, _>>();
         dbg!("Last 10:", &last10);
     }
+
+    #[test]
+    fn test_serialize_tagging_result() {
+        let result = TaggingResult::new(|category| match category {
+            TagCategory::General => {
+                Prediction::from([("solo".to_string(), 0.5), ("1girl".to_string(), 0.9)])
+            }
+            _ => Prediction::new(),
+        });
+
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(
+            json.contains(r#""general":{"1girl":0.9,"solo":0.5}"#),
+            "{}",
+            json
+        );
+
+        let deserialized: TaggingResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.general, result.general);
+        assert!(deserialized.get(&TagCategory::Artist).is_empty());
+    }
 }
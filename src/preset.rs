@@ -0,0 +1,168 @@
+use std::fmt::Display;
+
+/// Preset of the tagger models on Hugging Face
+pub trait ModelPreset {
+    /// Repository id on Hugging Face
+    fn repo_id(&self) -> String;
+    /// Name of the model in the [`crate::registry::ModelRegistry`]
+    fn name(&self) -> String;
+    fn default() -> Self;
+}
+
+/// Tagger models of v3 series
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum V3Model {
+    Vit,
+    SwinV2,
+    Convnext,
+    VitLarge,
+    Eva02Large,
+}
+
+impl V3Model {
+    /// All the models of v3 series
+    pub fn all() -> Vec<Self> {
+        vec![
+            V3Model::Vit,
+            V3Model::SwinV2,
+            V3Model::Convnext,
+            V3Model::VitLarge,
+            V3Model::Eva02Large,
+        ]
+    }
+}
+
+impl ModelPreset for V3Model {
+    fn repo_id(&self) -> String {
+        match self {
+            V3Model::Vit => "SmilingWolf/wd-vit-tagger-v3".to_string(),
+            V3Model::SwinV2 => "SmilingWolf/wd-swinv2-tagger-v3".to_string(),
+            V3Model::Convnext => "SmilingWolf/wd-convnext-tagger-v3".to_string(),
+            V3Model::VitLarge => "SmilingWolf/wd-vit-large-tagger-v3".to_string(),
+            V3Model::Eva02Large => "SmilingWolf/wd-eva02-large-tagger-v3".to_string(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            V3Model::Vit => "vit-v3".to_string(),
+            V3Model::SwinV2 => "swinv2-v3".to_string(),
+            V3Model::Convnext => "convnext-v3".to_string(),
+            V3Model::VitLarge => "vit-large-v3".to_string(),
+            V3Model::Eva02Large => "eva02-large-v3".to_string(),
+        }
+    }
+
+    fn default() -> Self {
+        V3Model::SwinV2
+    }
+}
+
+impl Display for V3Model {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            V3Model::Vit => write!(f, "vit"),
+            V3Model::SwinV2 => write!(f, "swin-v2"),
+            V3Model::Convnext => write!(f, "convnext"),
+            V3Model::VitLarge => write!(f, "vit-large"),
+            V3Model::Eva02Large => write!(f, "eva02-large"),
+        }
+    }
+}
+
+/// Tagger models of v2 series
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum V2Model {
+    Vit,
+    Moat,
+    SwinV2,
+    Convnext,
+    ConvnextV2,
+}
+
+impl V2Model {
+    /// All the models of v2 series
+    pub fn all() -> Vec<Self> {
+        vec![
+            V2Model::Vit,
+            V2Model::Moat,
+            V2Model::SwinV2,
+            V2Model::Convnext,
+            V2Model::ConvnextV2,
+        ]
+    }
+}
+
+impl ModelPreset for V2Model {
+    fn repo_id(&self) -> String {
+        match self {
+            V2Model::Vit => "SmilingWolf/wd-v1-4-vit-tagger-v2".to_string(),
+            V2Model::Moat => "SmilingWolf/wd-v1-4-moat-tagger-v2".to_string(),
+            V2Model::SwinV2 => "SmilingWolf/wd-v1-4-swinv2-tagger-v2".to_string(),
+            V2Model::Convnext => "SmilingWolf/wd-v1-4-convnext-tagger-v2".to_string(),
+            V2Model::ConvnextV2 => "SmilingWolf/wd-v1-4-convnextv2-tagger-v2".to_string(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            V2Model::Vit => "vit-v2".to_string(),
+            V2Model::Moat => "moat-v2".to_string(),
+            V2Model::SwinV2 => "swinv2-v2".to_string(),
+            V2Model::Convnext => "convnext-v2".to_string(),
+            V2Model::ConvnextV2 => "convnextv2-v2".to_string(),
+        }
+    }
+
+    fn default() -> Self {
+        V2Model::SwinV2
+    }
+}
+
+impl Display for V2Model {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            V2Model::Vit => write!(f, "vit"),
+            V2Model::Moat => write!(f, "moat"),
+            V2Model::SwinV2 => write!(f, "swin-v2"),
+            V2Model::Convnext => write!(f, "convnext"),
+            V2Model::ConvnextV2 => write!(f, "convnext-v2"),
+        }
+    }
+}
+
+/// Name and repository id of every preset
+pub fn presets() -> Vec<(String, String)> {
+    V3Model::all()
+        .iter()
+        .map(|model| (model.name(), model.repo_id()))
+        .chain(
+            V2Model::all()
+                .iter()
+                .map(|model| (model.name(), model.repo_id())),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let presets = presets();
+        assert_eq!(presets.len(), 10);
+        assert!(presets.contains(&(
+            "eva02-large-v3".to_string(),
+            "SmilingWolf/wd-eva02-large-tagger-v3".to_string()
+        )));
+
+        // names are unique
+        let mut names = presets.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), presets.len());
+    }
+}
@@ -1,8 +1,11 @@
 use crate::config::ModelConfig;
 use crate::error::TaggerError;
+use crate::file::HfOptions;
+use crate::tagger::TensorInfo;
 use anyhow::Result;
 use image::{DynamicImage, GenericImageView, ImageBuffer, RgbImage, Rgba};
 use ndarray::{Array, Axis, Ix4};
+use serde::{Deserialize, Serialize};
 
 pub trait ImageProcessor {
     fn process(&self, iamge: &DynamicImage) -> Result<Array<f32, Ix4>, TaggerError>;
@@ -29,22 +32,104 @@ pub trait ImageProcessor {
     }
 }
 
+/// Order of the dimensions of the input tensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// `[batch, height, width, channels]` like the models of SmilingWolf
+    Nhwc,
+    /// `[batch, channels, height, width]` like the models exported from PyTorch
+    Nchw,
+}
+
+/// Order of the color channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelOrder {
+    Bgr,
+    Rgb,
+}
+
+/// Range of the pixel values before the mean/std normalization
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum ValueRange {
+    /// 0 to 255
+    Byte,
+    /// 0 to 1
+    Unit,
+}
+
+/// Preprocessing that overrides the detected one. `None` is detected from the config and the model.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PreprocessOptions {
+    pub layout: Option<Layout>,
+    pub channel_order: Option<ChannelOrder>,
+    pub value_range: Option<ValueRange>,
+    /// Mean of each channel in RGB order, subtracted after scaling to the value range
+    pub mean: Option<Vec<f32>>,
+    /// Standard deviation of each channel in RGB order, dividing after subtracting the mean
+    pub std: Option<Vec<f32>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ImagePreprocessor {
     channels: u32,
     height: u32,
     width: u32,
+    layout: Layout,
+    channel_order: ChannelOrder,
+    value_range: ValueRange,
+    /// Mean of each channel in RGB order
+    mean: [f32; 3],
+    /// Standard deviation of each channel in RGB order
+    std: [f32; 3],
 }
 
 impl ImagePreprocessor {
+    /// Create a preprocessor of the models of SmilingWolf,
+    /// which take NHWC tensors of BGR values from 0 to 255.
     pub fn new(channels: u32, height: u32, width: u32) -> Self {
         Self {
             channels,
             height,
             width,
+            layout: Layout::Nhwc,
+            channel_order: ChannelOrder::Bgr,
+            value_range: ValueRange::Byte,
+            mean: [0.0; 3],
+            std: [1.0; 3],
         }
     }
 
+    /// Set the layout of the input tensor.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Set the order of the color channels.
+    pub fn with_channel_order(mut self, channel_order: ChannelOrder) -> Self {
+        self.channel_order = channel_order;
+        self
+    }
+
+    /// Set the range of the pixel values.
+    pub fn with_value_range(mut self, value_range: ValueRange) -> Self {
+        self.value_range = value_range;
+        self
+    }
+
+    /// Set the mean and standard deviation of each channel in RGB order.
+    pub fn with_normalization(mut self, mean: [f32; 3], std: [f32; 3]) -> Self {
+        self.mean = mean;
+        self.std = std;
+        self
+    }
+
     pub fn from_config(config: &ModelConfig) -> Result<Self, TaggerError> {
         let input_size = &config.pretrained_cfg.input_size;
         // check if the input size is valid
@@ -52,15 +137,68 @@ impl ImagePreprocessor {
             return Err(TaggerError::Processor("Invalid input size".to_string()));
         }
 
-        Ok(Self {
-            channels: input_size[0],
-            height: input_size[1],
-            width: input_size[2],
-        })
+        Ok(Self::new(input_size[0], input_size[1], input_size[2]))
+    }
+
+    /// Create a preprocessor for the input of the model, detecting the layout from its shape.
+    ///
+    /// An NHWC input is fed like the models of SmilingWolf, BGR values from 0 to 255.
+    /// An NCHW input is fed like the models exported from timm, RGB values from 0 to 1
+    /// normalized by the `mean` and `std` of the config.
+    /// Each of them is overridden by the options if specified.
+    pub fn detect(
+        config: &ModelConfig,
+        input: &TensorInfo,
+        options: &PreprocessOptions,
+    ) -> Result<Self, TaggerError> {
+        let preprocessor = Self::from_config(config)?;
+        let channels = preprocessor.channels as i64;
+
+        let layout = options.layout.unwrap_or(match input.dimensions.as_slice() {
+            [_, c, _, last] if *c == channels && *last != channels => Layout::Nchw,
+            _ => Layout::Nhwc,
+        });
+        let (channel_order, value_range, mean, std) = match layout {
+            Layout::Nhwc => (ChannelOrder::Bgr, ValueRange::Byte, None, None),
+            Layout::Nchw => (
+                ChannelOrder::Rgb,
+                ValueRange::Unit,
+                config.pretrained_cfg.mean.as_ref(),
+                config.pretrained_cfg.std.as_ref(),
+            ),
+        };
+        let mean = per_channel("mean", options.mean.as_ref().or(mean), 0.0)?;
+        let std = per_channel("std", options.std.as_ref().or(std), 1.0)?;
+        if std.contains(&0.0) {
+            return Err(TaggerError::Processor("std must not be zero".to_string()));
+        }
+
+        Ok(preprocessor
+            .with_layout(layout)
+            .with_channel_order(options.channel_order.unwrap_or(channel_order))
+            .with_value_range(options.value_range.unwrap_or(value_range))
+            .with_normalization(mean, std))
+    }
+
+    /// Shape of the input tensor of the model, `-1` for the batch size
+    pub fn input_shape(&self) -> [i64; 4] {
+        let (channels, height, width) =
+            (self.channels as i64, self.height as i64, self.width as i64);
+        match self.layout {
+            Layout::Nhwc => [-1, height, width, channels],
+            Layout::Nchw => [-1, channels, height, width],
+        }
     }
 
     pub fn from_pretrained(repo_id: &str) -> Result<Self, TaggerError> {
-        let config = ModelConfig::from_pretrained(repo_id)?;
+        Self::from_pretrained_with_options(repo_id, &HfOptions::from_env())
+    }
+
+    pub fn from_pretrained_with_options(
+        repo_id: &str,
+        options: &HfOptions,
+    ) -> Result<Self, TaggerError> {
+        let config = ModelConfig::from_pretrained_with_options(repo_id, options)?;
         Self::from_config(&config)
     }
 }
@@ -110,20 +248,55 @@ impl ImageProcessor for ImagePreprocessor {
             self.channels as usize,
         ));
 
-        // Convert to BGR and normalize
+        // Convert to the channel order and normalize
         // float32[batch_size,448,448,3]
+        let scale = match self.value_range {
+            ValueRange::Byte => 1.0,
+            ValueRange::Unit => 1.0 / 255.0,
+        };
+        // index of each output channel in RGB
+        let order = match self.channel_order {
+            ChannelOrder::Bgr => [2, 1, 0],
+            ChannelOrder::Rgb => [0, 1, 2],
+        };
         for (x, y, pixel) in resized_rgb.enumerate_pixels() {
-            let [r, g, b] = pixel.0;
-            image_tensor[[y as usize, x as usize, 0]] = b as f32;
-            image_tensor[[y as usize, x as usize, 1]] = g as f32;
-            image_tensor[[y as usize, x as usize, 2]] = r as f32;
+            for (channel, rgb) in order.iter().enumerate() {
+                let value = pixel.0[*rgb] as f32 * scale;
+                image_tensor[[y as usize, x as usize, channel]] =
+                    (value - self.mean[*rgb]) / self.std[*rgb];
+            }
         }
 
+        let image_tensor = match self.layout {
+            Layout::Nhwc => image_tensor,
+            Layout::Nchw => image_tensor
+                .permuted_axes([2, 0, 1])
+                .as_standard_layout()
+                .into_owned(),
+        };
+
         // Add batch dimension
         Ok(image_tensor.insert_axis(ndarray::Axis(0)))
     }
 }
 
+/// Get the value of each of the 3 channels, or the default if not specified.
+fn per_channel(
+    name: &str,
+    values: Option<&Vec<f32>>,
+    default: f32,
+) -> Result<[f32; 3], TaggerError> {
+    match values.map(|values| values.as_slice()) {
+        None => Ok([default; 3]),
+        Some([value]) => Ok([*value; 3]),
+        Some([r, g, b]) => Ok([*r, *g, *b]),
+        Some(values) => Err(TaggerError::Processor(format!(
+            "{} must have 1 or 3 values, got {:?}",
+            name, values
+        ))),
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -140,4 +313,92 @@ mod test {
         println!("{}", tensor);
         dbg!(tensor.shape());
     }
+
+    fn config(mean: Option<Vec<f32>>, std: Option<Vec<f32>>) -> ModelConfig {
+        ModelConfig {
+            architecture: "test".to_string(),
+            num_classes: 10,
+            num_features: 8,
+            pretrained_cfg: crate::config::PretrainedCfg {
+                input_size: vec![3, 4, 4],
+                fixed_input_size: true,
+                num_classes: 10,
+                mean,
+                std,
+            },
+        }
+    }
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, image::Rgb([255, 0, 51])))
+    }
+
+    #[test]
+    fn test_detect_nhwc() {
+        let config = config(Some(vec![0.5; 3]), Some(vec![0.5; 3]));
+        let processor = ImagePreprocessor::detect(
+            &config,
+            &TensorInfo::float32("input", vec![-1, 4, 4, 3]),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(processor.input_shape(), [-1, 4, 4, 3]);
+
+        // BGR from 0 to 255, ignoring the mean/std of the config
+        let tensor = processor.process(&image()).unwrap();
+        assert_eq!(tensor.shape(), &[1, 4, 4, 3]);
+        assert_eq!(tensor[[0, 0, 0, 0]], 51.0);
+        assert_eq!(tensor[[0, 0, 0, 2]], 255.0);
+    }
+
+    #[test]
+    fn test_detect_nchw() {
+        let config = config(Some(vec![0.5; 3]), Some(vec![0.5; 3]));
+        let processor = ImagePreprocessor::detect(
+            &config,
+            &TensorInfo::float32("input", vec![-1, 3, 4, 4]),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(processor.input_shape(), [-1, 3, 4, 4]);
+
+        // RGB from 0 to 1, normalized to -1 to 1
+        let tensor = processor.process(&image()).unwrap();
+        assert_eq!(tensor.shape(), &[1, 3, 4, 4]);
+        assert!((tensor[[0, 0, 1, 1]] - 1.0).abs() < 1e-6);
+        assert!((tensor[[0, 1, 1, 1]] + 1.0).abs() < 1e-6);
+        assert!((tensor[[0, 2, 1, 1]] + 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_detect_with_options() {
+        let config = config(None, None);
+        let options = PreprocessOptions {
+            channel_order: Some(ChannelOrder::Bgr),
+            value_range: Some(ValueRange::Byte),
+            mean: Some(vec![255.0]),
+            ..Default::default()
+        };
+        let processor = ImagePreprocessor::detect(
+            &config,
+            &TensorInfo::float32("input", vec![-1, 3, -1, -1]),
+            &options,
+        )
+        .unwrap();
+        let tensor = processor.process(&image()).unwrap();
+        assert_eq!(tensor.shape(), &[1, 3, 4, 4]);
+        assert_eq!(tensor[[0, 0, 0, 0]], 51.0 - 255.0);
+        assert_eq!(tensor[[0, 2, 0, 0]], 0.0);
+
+        let options = PreprocessOptions {
+            std: Some(vec![1.0, 2.0]),
+            ..Default::default()
+        };
+        assert!(ImagePreprocessor::detect(
+            &config,
+            &TensorInfo::float32("input", vec![-1, 3, 4, 4]),
+            &options
+        )
+        .is_err());
+    }
 }
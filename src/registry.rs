@@ -0,0 +1,510 @@
+use indexmap::IndexMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::backend::{Backend, SessionOptions};
+use crate::batch::AdaptiveBatcher;
+use crate::config::ModelConfig;
+use crate::error::TaggerError;
+use crate::file::{ConfigFile, HfFile, HfOptions, TagCSVFile, TaggerModelFile};
+use crate::pipeline::TaggingPipeline;
+use crate::preset::presets;
+use crate::processor::{ImagePreprocessor, PreprocessOptions};
+use crate::tagger::{Device, TaggerModel, TensorNames};
+use crate::tags::LabelTags;
+use crate::threshold::Threshold;
+
+/// Where to load a model from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelSource {
+    /// Repository on Hugging Face with `model.onnx`, `config.json` and `selected_tags.csv`
+    Hub(String),
+    /// Local folder with `model.onnx`, `config.json` and `selected_tags.csv`
+    Dir(PathBuf),
+    /// Local model, config and tag list files
+    Files {
+        model: PathBuf,
+        config: PathBuf,
+        tags: PathBuf,
+    },
+}
+
+impl Display for ModelSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelSource::Hub(repo_id) => write!(f, "{}", repo_id),
+            ModelSource::Dir(dir) => write!(f, "{}", dir.display()),
+            ModelSource::Files { model, .. } => write!(f, "{}", model.display()),
+        }
+    }
+}
+
+/// Options to load a model, for the backend and the models that do not follow
+/// the conventions of the models of SmilingWolf
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadOptions {
+    /// Names of the input and output tensors
+    pub names: TensorNames,
+    /// Preprocessing instead of the detected one
+    pub preprocess: PreprocessOptions,
+    /// Inference backend to run the model with
+    pub backend: Backend,
+    /// Options of the session of ONNX Runtime
+    pub session: SessionOptions,
+}
+
+/// Status of a registered model
+#[derive(Debug, Clone)]
+pub struct ModelStatus {
+    pub name: String,
+    pub source: ModelSource,
+    /// Estimated memory usage in bytes if loaded
+    pub memory: Option<u64>,
+    /// Threshold of the pipeline if loaded
+    pub threshold: Option<Threshold>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    source: ModelSource,
+    /// Options to load the model, or `None` for the default options of the registry
+    options: Option<LoadOptions>,
+    loaded: Option<Loaded>,
+    /// Registered by [`ModelRegistry::get`] as a repository id, and removed if it fails to load
+    auto_registered: bool,
+    /// Held while the model is loading, so that the other callers wait for it
+    /// instead of loading it again
+    loading: Arc<Mutex<()>>,
+}
+
+impl Entry {
+    fn new(source: ModelSource, options: Option<LoadOptions>) -> Self {
+        Self {
+            source,
+            options,
+            loaded: None,
+            auto_registered: false,
+            loading: Arc::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Loaded {
+    pipeline: Arc<TaggingPipeline>,
+    /// Batcher of the model, dropped with it on eviction
+    batcher: Arc<AdaptiveBatcher>,
+    /// Estimated memory usage in bytes
+    memory: u64,
+    /// Tick of the last use, to evict the least recently used model first
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: IndexMap<String, Entry>,
+    tick: u64,
+}
+
+impl State {
+    /// Get the pipeline and the batcher of the model if loaded, and mark it as the most recently used.
+    fn touch(&mut self, name: &str) -> Option<(Arc<TaggingPipeline>, Arc<AdaptiveBatcher>)> {
+        self.tick += 1;
+        let tick = self.tick;
+        let loaded = self.entries.get_mut(name)?.loaded.as_mut()?;
+        loaded.last_used = tick;
+        Some((loaded.pipeline.clone(), loaded.batcher.clone()))
+    }
+
+    /// Check if the entry of the name is still the one that was being loaded with the lock.
+    fn is_loading(&self, name: &str, loading: &Arc<Mutex<()>>) -> bool {
+        self.entries
+            .get(name)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.loading, loading))
+    }
+}
+
+/// Registry of the models that are loaded lazily by name and cached.
+///
+/// When the estimated memory usage of the loaded models exceeds the budget,
+/// the least recently used models are evicted. A pipeline that is still in use keeps working
+/// after its eviction, and is loaded again on the next [`ModelRegistry::get`].
+#[derive(Debug)]
+pub struct ModelRegistry {
+    state: Mutex<State>,
+    devices: Vec<Device>,
+    options: HfOptions,
+    load_options: LoadOptions,
+    threshold: Threshold,
+    memory_budget: Option<u64>,
+    auto_register: bool,
+    /// Settings of the batcher of each loaded model
+    batcher: AdaptiveBatcher,
+}
+
+impl ModelRegistry {
+    /// Create an empty registry that loads the models on the devices.
+    pub fn new(devices: Vec<Device>) -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            devices,
+            options: HfOptions::from_env(),
+            load_options: LoadOptions::default(),
+            threshold: Threshold::default(),
+            memory_budget: None,
+            auto_register: false,
+            batcher: AdaptiveBatcher::new(1),
+        }
+    }
+
+    /// Register the presets of v2 and v3 series by their names (e.g. `swinv2-v3`),
+    /// keeping the models already registered under the same names.
+    pub fn with_presets(self) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            for (name, repo_id) in presets() {
+                state
+                    .entries
+                    .entry(name)
+                    .or_insert_with(|| Entry::new(ModelSource::Hub(repo_id), None));
+            }
+        }
+        self
+    }
+
+    /// Set the options to access the Hugging Face Hub.
+    pub fn with_hf_options(mut self, options: HfOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Set the options to load the models registered without options,
+    /// e.g. the backend and the session options of all the models.
+    pub fn with_load_options(mut self, options: LoadOptions) -> Self {
+        self.load_options = options;
+        self
+    }
+
+    /// Set the threshold of the loaded pipelines.
+    pub fn with_threshold(mut self, threshold: Threshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Set the memory budget in bytes. The memory usage of a model is estimated by the size of its ONNX file.
+    pub fn with_memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Set the batcher of the models. Each loaded model gets a new one with the same settings,
+    /// so that the batch size adapts to each model. One image at a time by default.
+    pub fn with_batcher(mut self, batcher: AdaptiveBatcher) -> Self {
+        self.batcher = batcher;
+        self
+    }
+
+    /// Register the names with a `/` that are not registered as repository ids on Hugging Face
+    /// when they are requested. Off by default, since any repository can be downloaded
+    /// with the token of the registry.
+    pub fn with_auto_register(mut self, enable: bool) -> Self {
+        self.auto_register = enable;
+        self
+    }
+
+    /// Register a model by name, replacing the model of the same name.
+    /// The model is loaded with the load options of the registry.
+    pub fn register(&self, name: &str, source: ModelSource) {
+        self.insert(name, source, None);
+    }
+
+    /// Register a model by name with the options to load it,
+    /// replacing the model of the same name.
+    pub fn register_with_options(&self, name: &str, source: ModelSource, options: LoadOptions) {
+        self.insert(name, source, Some(options));
+    }
+
+    fn insert(&self, name: &str, source: ModelSource, options: Option<LoadOptions>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .entries
+            .insert(name.to_string(), Entry::new(source, options));
+    }
+
+    /// Remove a model from the registry.
+    pub fn unregister(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.entries.shift_remove(name).is_some()
+    }
+
+    /// Unload a model, keeping it registered.
+    pub fn unload(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get_mut(name) {
+            Some(entry) => entry.loaded.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// Get the pipeline of the model, loading it if needed.
+    /// With [`ModelRegistry::with_auto_register`], a name with a `/` that is not registered
+    /// is registered as a repository id on Hugging Face.
+    ///
+    /// The callers of a model that is loading wait for it, but the other models are not blocked.
+    pub fn get(&self, name: &str) -> Result<Arc<TaggingPipeline>, TaggerError> {
+        self.get_with_batcher(name).map(|(pipeline, _)| pipeline)
+    }
+
+    /// Get the pipeline of the model like [`ModelRegistry::get`], and the batcher of the model.
+    pub fn get_with_batcher(
+        &self,
+        name: &str,
+    ) -> Result<(Arc<TaggingPipeline>, Arc<AdaptiveBatcher>), TaggerError> {
+        let (loading, source, options) = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(loaded) = state.touch(name) {
+                return Ok(loaded);
+            }
+            if !state.entries.contains_key(name) {
+                if !(self.auto_register && name.contains('/')) {
+                    return Err(TaggerError::Registry(format!("Unknown model: {}", name)));
+                }
+                let mut entry = Entry::new(ModelSource::Hub(name.to_string()), None);
+                entry.auto_registered = true;
+                state.entries.insert(name.to_string(), entry);
+            }
+            let entry = &state.entries[name];
+            (
+                entry.loading.clone(),
+                entry.source.clone(),
+                entry.options.clone(),
+            )
+        };
+
+        let _loading = loading.lock().unwrap_or_else(PoisonError::into_inner);
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(loaded) = state.touch(name) {
+                // loaded by another caller while waiting
+                return Ok(loaded);
+            }
+            if !state.is_loading(name, &loading) {
+                return Err(TaggerError::Registry(format!(
+                    "The model was unregistered while loading: {}",
+                    name
+                )));
+            }
+        }
+
+        let options = options.unwrap_or_else(|| self.load_options.clone());
+        let result = self.files(&source).and_then(|files| {
+            let memory = std::fs::metadata(&files.0)
+                .map_err(|e| TaggerError::Io(format!("{}: {}", files.0.display(), e)))?
+                .len();
+            Ok((self.load(files, &options)?, memory))
+        });
+
+        let batcher = Arc::new(self.batcher.fresh());
+        let mut state = self.state.lock().unwrap();
+        let (pipeline, memory) = match result {
+            Ok((pipeline, memory)) => (Arc::new(pipeline), memory),
+            Err(e) => {
+                // a repository that was never loaded does not stay registered
+                if state.is_loading(name, &loading) && state.entries[name].auto_registered {
+                    state.entries.shift_remove(name);
+                }
+                return Err(e);
+            }
+        };
+        if !state.is_loading(name, &loading) {
+            // replaced or unregistered while loading, so it is not cached
+            return Ok((pipeline, batcher));
+        }
+
+        // evict only after the model is loaded, so that a failure keeps the other models loaded
+        if let Some(budget) = self.memory_budget {
+            let loaded = state
+                .entries
+                .iter()
+                .filter_map(|(name, entry)| {
+                    entry
+                        .loaded
+                        .as_ref()
+                        .map(|loaded| (name.clone(), loaded.memory, loaded.last_used))
+                })
+                .collect::<Vec<_>>();
+            for evicted in evictions(&loaded, memory, budget) {
+                if let Some(entry) = state.entries.get_mut(&evicted) {
+                    entry.loaded = None;
+                }
+            }
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.entries.get_mut(name).unwrap().loaded = Some(Loaded {
+            pipeline: pipeline.clone(),
+            batcher: batcher.clone(),
+            memory,
+            last_used: tick,
+        });
+
+        Ok((pipeline, batcher))
+    }
+
+    /// Status of the registered models in the order of registration.
+    pub fn models(&self) -> Vec<ModelStatus> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .iter()
+            .map(|(name, entry)| ModelStatus {
+                name: name.clone(),
+                source: entry.source.clone(),
+                memory: entry.loaded.as_ref().map(|loaded| loaded.memory),
+                threshold: entry
+                    .loaded
+                    .as_ref()
+                    .map(|loaded| loaded.pipeline.threshold().clone()),
+            })
+            .collect()
+    }
+
+    /// Estimated memory usage of the loaded models in bytes.
+    pub fn memory_usage(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .values()
+            .filter_map(|entry| entry.loaded.as_ref().map(|loaded| loaded.memory))
+            .sum()
+    }
+
+    /// Get the model, config and tag list files of the source, downloading them if needed.
+    fn files(&self, source: &ModelSource) -> Result<(PathBuf, PathBuf, PathBuf), TaggerError> {
+        let files = match source {
+            ModelSource::Hub(repo_id) => (
+                TaggerModelFile::new(repo_id).get_with_options(&self.options)?,
+                ConfigFile::new(repo_id).get_with_options(&self.options)?,
+                TagCSVFile::new(repo_id).get_with_options(&self.options)?,
+            ),
+            ModelSource::Dir(dir) => (
+                dir.join("model.onnx"),
+                dir.join("config.json"),
+                dir.join("selected_tags.csv"),
+            ),
+            ModelSource::Files {
+                model,
+                config,
+                tags,
+            } => (model.clone(), config.clone(), tags.clone()),
+        };
+        Ok(files)
+    }
+
+    /// Load the pipeline from the model, config and tag list files.
+    fn load(
+        &self,
+        (model_path, config_path, tags_path): (PathBuf, PathBuf, PathBuf),
+        options: &LoadOptions,
+    ) -> Result<TaggingPipeline, TaggerError> {
+        let session = options.session.clone().or_devices(&self.devices);
+        let model =
+            TaggerModel::load_with_backend(&model_path, &options.names, options.backend, &session)?;
+        let config = ModelConfig::load(&config_path)?;
+        let preprocessor = ImagePreprocessor::detect(&config, model.input(), &options.preprocess)?;
+        model.validate(&config, &preprocessor)?;
+        let tags = LabelTags::load(&tags_path)?;
+
+        Ok(TaggingPipeline::new(
+            model,
+            preprocessor,
+            tags,
+            self.threshold.clone(),
+        ))
+    }
+}
+
+/// Get the names of the models to evict so that a new model of `incoming` bytes fits in the budget,
+/// the least recently used first. `loaded` is the (name, memory, last used) of each loaded model.
+fn evictions(loaded: &[(String, u64, u64)], incoming: u64, budget: u64) -> Vec<String> {
+    let mut loaded = loaded.to_vec();
+    loaded.sort_by_key(|(_, _, last_used)| *last_used);
+
+    let mut usage = loaded.iter().map(|(_, memory, _)| memory).sum::<u64>() + incoming;
+    let mut evicted = vec![];
+    for (name, memory, _) in loaded {
+        if usage <= budget {
+            break;
+        }
+        usage -= memory;
+        evicted.push(name);
+    }
+    evicted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_evictions() {
+        let loaded = vec![
+            ("a".to_string(), 300, 3),
+            ("b".to_string(), 200, 1),
+            ("c".to_string(), 100, 2),
+        ];
+
+        // fits without eviction
+        assert!(evictions(&loaded, 100, 700).is_empty());
+        // the least recently used first
+        assert_eq!(evictions(&loaded, 100, 600), vec!["b"]);
+        assert_eq!(evictions(&loaded, 300, 600), vec!["b", "c"]);
+        // a model larger than the budget evicts everything
+        assert_eq!(evictions(&loaded, 1000, 600), vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_auto_register() {
+        // offline with an empty cache, so that nothing is found
+        let options = HfOptions {
+            cache_dir: Some(std::env::temp_dir().join("wdtagger-empty-cache")),
+            offline: true,
+            ..Default::default()
+        };
+
+        let registry = ModelRegistry::new(Device::cpu()).with_hf_options(options.clone());
+        assert!(matches!(
+            registry.get("someone/model"),
+            Err(TaggerError::Registry(_))
+        ));
+        assert!(registry.models().is_empty());
+
+        // the repository that fails to load is not kept registered
+        let registry = ModelRegistry::new(Device::cpu())
+            .with_hf_options(options)
+            .with_auto_register(true);
+        assert!(registry.get("someone/model").is_err());
+        assert!(registry.models().is_empty());
+    }
+
+    #[test]
+    fn test_register_presets() {
+        let registry = ModelRegistry::new(Device::cpu());
+        registry.register("vit-v3", ModelSource::Dir("path/to/model".into()));
+        let registry = registry.with_presets();
+
+        assert!(registry.get("unknown").is_err());
+        assert_eq!(registry.models().len(), 10);
+        assert!(registry.models().iter().any(|model| model.name == "vit-v3"
+            && model.source == ModelSource::Dir("path/to/model".into())));
+        assert!(registry
+            .models()
+            .iter()
+            .any(|model| model.name == "swinv2-v3"
+                && model.source
+                    == ModelSource::Hub("SmilingWolf/wd-swinv2-tagger-v3".to_string())
+                && model.memory.is_none()));
+    }
+}
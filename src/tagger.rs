@@ -1,20 +1,17 @@
+use std::fmt::Display;
 use std::path::Path;
 
 use anyhow::Result;
-use ndarray::{Array, Axis, Ix4};
-use ort::{CPUExecutionProvider, Session};
-
-#[cfg(feature = "cuda")]
-use ort::CUDAExecutionProvider;
-
-#[cfg(feature = "tensorrt")]
-use ort::TensorRTExecutionProvider;
+use ndarray::{Array, ArrayD, Axis, Ix4};
 
+use crate::backend::{Backend, InferenceBackend, SessionOptions};
+use crate::config::ModelConfig;
 use crate::error::TaggerError;
-use crate::file::{HfFile, TaggerModelFile};
+use crate::file::{HfFile, HfOptions, TaggerModelFile};
+use crate::processor::ImagePreprocessor;
 
 /// Enum for selecting the CUDA device
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Device {
     Cpu,
     /// CUDA with default device
@@ -31,6 +28,22 @@ pub enum Device {
     TensorRTDevice(i32),
 }
 
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Device::Cpu => write!(f, "CPU"),
+            #[cfg(feature = "cuda")]
+            Device::Cuda => write!(f, "CUDA"),
+            #[cfg(feature = "cuda")]
+            Device::CudaDevice(device_id) => write!(f, "CUDA:{}", device_id),
+            #[cfg(feature = "tensorrt")]
+            Device::TensorRT => write!(f, "TensorRT"),
+            #[cfg(feature = "tensorrt")]
+            Device::TensorRTDevice(device_id) => write!(f, "TensorRT:{}", device_id),
+        }
+    }
+}
+
 /// Ailas for the device
 impl Device {
     /// Use CPU
@@ -63,78 +76,241 @@ impl Device {
     }
 }
 
-/// Model for the Tagger
-#[derive(Debug)]
+/// Name of the output of the tag probabilities in the models of SmilingWolf
+const DEFAULT_OUTPUT_NAME: &str = "output";
 
-pub struct TaggerModel {
-    session: Session,
+/// Element type of a tensor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataType {
+    /// The type of the input and output tensors of the models
+    Float32,
+    /// Any other type, by its name in the backend
+    Other(String),
 }
 
-impl TaggerModel {
-    /// Specify the devices to use
-    pub fn use_devices(devices: Vec<Device>) -> Result<(), TaggerError> {
-        match tracing_subscriber::fmt::try_init() {
-            Ok(_) => {}
-            Err(e) => println!("Warning: Failed to initialize the logger: {}", e),
+impl Display for DataType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataType::Float32 => write!(f, "Float32"),
+            DataType::Other(name) => write!(f, "{}", name),
         }
+    }
+}
 
-        let privders = devices
-            .iter()
-            .map(|device| match device {
-                Device::Cpu => CPUExecutionProvider::default().build(),
-                #[cfg(feature = "cuda")]
-                Device::Cuda => CUDAExecutionProvider::default().build(),
-                #[cfg(feature = "cuda")]
-                Device::CudaDevice(device_id) => {
-                    let provider = CUDAExecutionProvider::default();
-                    provider.with_device_id(device_id.clone()).build()
-                }
-                #[cfg(feature = "tensorrt")]
-                Device::TensorRT => TensorRTExecutionProvider::default().build(),
-                #[cfg(feature = "tensorrt")]
-                Device::TensorRTDevice(device_id) => {
-                    let provider = TensorRTExecutionProvider::default();
-                    provider.with_device_id(device_id.clone()).build()
-                }
-            })
-            .collect::<Vec<_>>();
+/// Name, element type and shape of an input or output of the model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub name: String,
+    /// Element type of the tensor, or `None` if it is not a tensor
+    pub dtype: Option<DataType>,
+    /// Dimensions of the tensor, `-1` for a dynamic one
+    pub dimensions: Vec<i64>,
+}
 
-        match ort::init().with_execution_providers(privders).commit() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(TaggerError::Cuda(e.to_string())),
+impl TensorInfo {
+    /// Float32 tensor of the dimensions, e.g. the inputs and outputs of the tests
+    #[cfg(test)]
+    pub(crate) fn float32(name: &str, dimensions: Vec<i64>) -> Self {
+        Self {
+            name: name.to_string(),
+            dtype: Some(DataType::Float32),
+            dimensions,
         }
     }
 
+    /// Check that the tensor is f32 with the dimensions. `-1` in either one matches any size.
+    fn check(&self, kind: &str, dimensions: &[i64]) -> Result<(), TaggerError> {
+        match &self.dtype {
+            Some(DataType::Float32) => {}
+            Some(dtype) => {
+                return Err(TaggerError::Ort(format!(
+                    "The {} {} must be a {} tensor, but is {}",
+                    kind,
+                    self.name,
+                    DataType::Float32,
+                    dtype
+                )))
+            }
+            None => {
+                return Err(TaggerError::Ort(format!(
+                    "The {} {} must be a {} tensor, but is not a tensor",
+                    kind,
+                    self.name,
+                    DataType::Float32
+                )))
+            }
+        }
+        let matches = self.dimensions.len() == dimensions.len()
+            && self
+                .dimensions
+                .iter()
+                .zip(dimensions)
+                .all(|(actual, expected)| *actual < 0 || *expected < 0 || actual == expected);
+        if !matches {
+            return Err(TaggerError::Ort(format!(
+                "The {} {} has the shape {:?}, expected {:?}",
+                kind, self.name, self.dimensions, dimensions
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Names of the input and output to use instead of the detected ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TensorNames {
+    /// Input of the image tensor. The first input by default.
+    pub input: Option<String>,
+    /// Output of the tag probabilities. `output`, or the first output, by default.
+    pub output: Option<String>,
+}
+
+/// Model for the Tagger
+#[derive(Debug)]
+pub struct TaggerModel {
+    backend: Box<dyn InferenceBackend>,
+    input: TensorInfo,
+    output: TensorInfo,
+}
+
+impl TaggerModel {
     /// Load the model directly using the local file path
     pub fn load<P: AsRef<Path>>(model_path: P) -> Result<Self, TaggerError> {
-        let builder = match Session::builder() {
-            Ok(builder) => builder,
-            Err(e) => return Err(TaggerError::Ort(e.to_string())),
-        };
+        Self::load_with_names(model_path, &TensorNames::default())
+    }
 
-        let session = builder
-            .commit_from_file(model_path)
-            .map_err(|e| TaggerError::Ort(e.to_string()))?;
+    /// Load the model using the local file path with the specified session options
+    pub fn load_with_session<P: AsRef<Path>>(
+        model_path: P,
+        session: &SessionOptions,
+    ) -> Result<Self, TaggerError> {
+        Self::load_with_backend(
+            model_path,
+            &TensorNames::default(),
+            Backend::default(),
+            session,
+        )
+    }
+
+    /// Load the model using the local file path with the specified input and output names
+    pub fn load_with_names<P: AsRef<Path>>(
+        model_path: P,
+        names: &TensorNames,
+    ) -> Result<Self, TaggerError> {
+        Self::load_with_backend(
+            model_path,
+            names,
+            Backend::default(),
+            &SessionOptions::default(),
+        )
+    }
 
-        Ok(Self { session })
+    /// Load the model using the local file path with the specified names, inference backend
+    /// and session options
+    pub fn load_with_backend<P: AsRef<Path>>(
+        model_path: P,
+        names: &TensorNames,
+        backend: Backend,
+        session: &SessionOptions,
+    ) -> Result<Self, TaggerError> {
+        Self::from_backend(backend.load(model_path, session)?, names)
+    }
+
+    /// Create the model on a loaded inference backend
+    pub fn from_backend(
+        backend: Box<dyn InferenceBackend>,
+        names: &TensorNames,
+    ) -> Result<Self, TaggerError> {
+        let input = select_tensor("input", backend.inputs(), names.input.as_deref(), None)?;
+        let output = select_tensor(
+            "output",
+            backend.outputs(),
+            names.output.as_deref(),
+            Some(DEFAULT_OUTPUT_NAME),
+        )?;
+
+        Ok(Self {
+            backend,
+            input,
+            output,
+        })
     }
 
     /// Load the model in user-friendly way using the repo_id
     pub fn from_pretrained(repo_id: &str) -> Result<Self, TaggerError> {
-        let model_path = TaggerModelFile::new(repo_id)
-            .get()
-            .map_err(|e| TaggerError::Hf(e.to_string()))?;
+        Self::from_pretrained_with_options(repo_id, &HfOptions::from_env())
+    }
+
+    /// Load the model using the repo_id with the specified HuggingFace options
+    pub fn from_pretrained_with_options(
+        repo_id: &str,
+        options: &HfOptions,
+    ) -> Result<Self, TaggerError> {
+        let model_path = TaggerModelFile::new(repo_id).get_with_options(options)?;
 
         Self::load(model_path)
     }
 
+    /// Load the model using the repo_id with the specified HuggingFace and session options
+    pub fn from_pretrained_with_session(
+        repo_id: &str,
+        options: &HfOptions,
+        session: &SessionOptions,
+    ) -> Result<Self, TaggerError> {
+        let model_path = TaggerModelFile::new(repo_id).get_with_options(options)?;
+
+        Self::load_with_session(model_path, session)
+    }
+
+    /// Input of the image tensor
+    pub fn input(&self) -> &TensorInfo {
+        &self.input
+    }
+
+    /// Output of the tag probabilities
+    pub fn output(&self) -> &TensorInfo {
+        &self.output
+    }
+
+    /// Inference backend running the model
+    pub fn backend(&self) -> &dyn InferenceBackend {
+        self.backend.as_ref()
+    }
+
+    /// Device that serves the session, which is not the requested one
+    /// if ONNX Runtime is built without its execution provider
+    pub fn device(&self) -> Device {
+        self.backend.device()
+    }
+
+    /// Names of the outputs of the model
+    pub fn output_names(&self) -> Vec<String> {
+        self.backend
+            .outputs()
+            .iter()
+            .map(|output| output.name.clone())
+            .collect()
+    }
+
+    /// Check that the input takes the float32 tensors of the preprocessor.
+    pub fn validate_input(&self, preprocessor: &ImagePreprocessor) -> Result<(), TaggerError> {
+        self.input.check("input", &preprocessor.input_shape())
+    }
+
+    /// Check that the input takes the float32 tensors of the preprocessor,
+    /// and that the output has a float32 probability per class of the config.
+    pub fn validate(
+        &self,
+        config: &ModelConfig,
+        preprocessor: &ImagePreprocessor,
+    ) -> Result<(), TaggerError> {
+        self.validate_input(preprocessor)?;
+        self.output
+            .check("output", &[-1, config.num_classes as i64])
+    }
+
     pub fn predict(&self, input_tensor: Array<f32, Ix4>) -> Result<Vec<Vec<f32>>, TaggerError> {
-        let inputs = ort::inputs![input_tensor].map_err(|e| TaggerError::Ort(e.to_string()))?;
-        let output = self
-            .session
-            .run(inputs)
-            .map_err(|e| TaggerError::Ort(e.to_string()))?;
-        let preds = output["output"].try_extract_tensor::<f32>().unwrap();
+        let preds = self.predict_output(input_tensor, &self.output.name)?;
 
         let preds = preds
             .axis_iter(Axis(0))
@@ -143,6 +319,49 @@ impl TaggerModel {
 
         Ok(preds)
     }
+
+    /// Run the model and get the output of the specified name as it is
+    pub fn predict_output(
+        &self,
+        input_tensor: Array<f32, Ix4>,
+        output_name: &str,
+    ) -> Result<ArrayD<f32>, TaggerError> {
+        self.backend
+            .run(&self.input.name, input_tensor, output_name)
+    }
+}
+
+/// Select the tensor of the requested name, or the default one, or the first one.
+fn select_tensor(
+    kind: &str,
+    tensors: &[TensorInfo],
+    requested: Option<&str>,
+    default: Option<&str>,
+) -> Result<TensorInfo, TaggerError> {
+    let names = || {
+        tensors
+            .iter()
+            .map(|tensor| tensor.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let find = |name: &str| tensors.iter().find(|tensor| tensor.name == name).cloned();
+
+    match requested {
+        Some(name) => find(name).ok_or_else(|| {
+            TaggerError::Ort(format!(
+                "The model has no {} named {}. The {}s are: {}",
+                kind,
+                name,
+                kind,
+                names()
+            ))
+        }),
+        None => default
+            .and_then(find)
+            .or_else(|| tensors.first().cloned())
+            .ok_or_else(|| TaggerError::Ort(format!("The model has no {}", kind))),
+    }
 }
 
 #[cfg(test)]
@@ -152,40 +371,81 @@ mod test {
     use crate::processor::{ImagePreprocessor, ImageProcessor};
     use image;
     use ndarray::Axis;
-    use ort::SessionOutputs;
+
+    #[test]
+    fn test_select_tensor() {
+        let outputs = vec![
+            TensorInfo::float32("logits", vec![-1, 10]),
+            TensorInfo::float32("output", vec![-1, 10]),
+        ];
+
+        let output = select_tensor("output", &outputs, None, Some(DEFAULT_OUTPUT_NAME)).unwrap();
+        assert_eq!(output.name, "output");
+        let output = select_tensor("output", &outputs, Some("logits"), None).unwrap();
+        assert_eq!(output.name, "logits");
+        assert!(select_tensor("output", &outputs, Some("probs"), None).is_err());
+
+        // the first one without the default
+        let inputs = vec![TensorInfo::float32("input_1:0", vec![-1, 448, 448, 3])];
+        let input = select_tensor("input", &inputs, None, None).unwrap();
+        assert_eq!(input.name, "input_1:0");
+        assert!(select_tensor("input", &[], None, None).is_err());
+    }
+
+    #[test]
+    fn test_check_tensor() {
+        let input = TensorInfo::float32("input", vec![-1, 448, 448, 3]);
+        assert!(input.check("input", &[-1, 448, 448, 3]).is_ok());
+        assert!(input.check("input", &[1, 448, 448, 3]).is_ok());
+        assert!(input.check("input", &[-1, 3, 448, 448]).is_err());
+        assert!(input.check("input", &[-1, 448, 448]).is_err());
+
+        // dynamic size
+        let input = TensorInfo::float32("input", vec![-1, -1, -1, 3]);
+        assert!(input.check("input", &[-1, 384, 384, 3]).is_ok());
+
+        let input = TensorInfo {
+            dtype: Some(DataType::Other("Uint8".to_string())),
+            ..input
+        };
+        assert!(input.check("input", &[-1, 384, 384, 3]).is_err());
+    }
+
+    fn load_on(devices: Vec<Device>) -> Result<TaggerModel, TaggerError> {
+        let model_path = TaggerModelFile::new("SmilingWolf/wd-swinv2-tagger-v3")
+            .get()
+            .unwrap();
+        let session = SessionOptions::default().with_devices(devices);
+        TaggerModel::load_with_session(model_path, &session)
+    }
 
     #[test]
     fn test_use_cpu() {
-        let devices = vec![Device::Cpu];
-        assert!(TaggerModel::use_devices(devices).is_ok());
+        assert!(load_on(vec![Device::Cpu]).is_ok());
     }
 
     #[test]
     #[cfg(feature = "cuda")]
     fn test_use_cuda_auto() {
-        let devices = vec![Device::Cuda];
-        assert!(TaggerModel::use_devices(devices).is_ok());
+        assert!(load_on(vec![Device::Cuda]).is_ok());
     }
 
     #[test]
     #[cfg(feature = "cuda")]
     fn test_use_cuda_device() {
-        let devices = vec![Device::CudaDevice(0)];
-        assert!(TaggerModel::use_devices(devices).is_ok());
+        assert!(load_on(vec![Device::CudaDevice(0)]).is_ok());
     }
 
     #[test]
     #[cfg(feature = "tensorrt")]
     fn test_use_tensorrt() {
-        let devices = vec![Device::TensorRT];
-        assert!(TaggerModel::use_devices(devices).is_ok());
+        assert!(load_on(vec![Device::TensorRT]).is_ok());
     }
 
     #[test]
     #[cfg(feature = "tensorrt")]
     fn test_use_tensorrt_device() {
-        let devices = vec![Device::TensorRTDevice(0)];
-        assert!(TaggerModel::use_devices(devices).is_ok());
+        assert!(load_on(vec![Device::TensorRTDevice(0)]).is_ok());
     }
 
     #[test]
@@ -213,15 +473,9 @@ mod test {
         let image = image::open("assets/sample1_3x1024x1024.webp").unwrap();
         let processor = ImagePreprocessor::new(3, 448, 448);
         let tensor = processor.process(&image).unwrap();
-        let inputs = ort::inputs![tensor].unwrap();
 
-        let output: SessionOutputs = model.session.run(inputs).unwrap();
-        let preds = output["output"]
-            .try_extract_tensor::<f32>()
-            .unwrap()
-            .into_owned();
+        let preds = model.predict_output(tensor, "output").unwrap();
 
-        dbg!(&output);
         println!("{}", &preds);
 
         let preds = preds
@@ -240,4 +494,28 @@ mod test {
         let pairs = preds.iter().take(5).collect::<Vec<_>>();
         dbg!("Pairs:", &pairs); // [general, sensitive, questionable, explicit, 1girl]
     }
+
+    #[test]
+    #[cfg(feature = "tract")]
+    fn test_run_tagger_model_tract() {
+        let model_path = TaggerModelFile::new("SmilingWolf/wd-swinv2-tagger-v3")
+            .get()
+            .unwrap();
+
+        let model = TaggerModel::load_with_backend(
+            model_path,
+            &TensorNames::default(),
+            Backend::Tract,
+            &SessionOptions::default(),
+        )
+        .unwrap();
+
+        let image = image::open("assets/sample1_3x1024x1024.webp").unwrap();
+        let processor = ImagePreprocessor::new(3, 448, 448);
+        let tensor = processor.process(&image).unwrap();
+
+        let preds = model.predict(tensor).unwrap();
+        assert_eq!(preds.len(), 1);
+        assert_eq!(preds[0].len(), 10861);
+    }
 }
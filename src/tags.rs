@@ -6,7 +6,7 @@ use anyhow::Result;
 use serde::Deserialize;
 
 use crate::error::TaggerError;
-use crate::file::{HfFile, TagCSVFile};
+use crate::file::{HfFile, HfOptions, TagCSVFile};
 
 /// Each record in the CSV file
 #[derive(Debug, Deserialize, Clone)]
@@ -18,7 +18,7 @@ pub struct Tag {
 }
 
 /// Tag category
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum TagCategory {
     #[serde(rename = "0")]
     General,
@@ -34,6 +34,32 @@ pub enum TagCategory {
     Rating,
 }
 
+impl TagCategory {
+    /// Human-readable name of the category
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::General => "general",
+            Self::Artist => "artist",
+            Self::Copyright => "copyright",
+            Self::Character => "character",
+            Self::Meta => "meta",
+            Self::Rating => "rating",
+        }
+    }
+
+    /// All the categories
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::General,
+            Self::Artist,
+            Self::Copyright,
+            Self::Character,
+            Self::Meta,
+            Self::Rating,
+        ]
+    }
+}
+
 impl Tag {
     pub fn category(&self) -> TagCategory {
         self.category.clone()
@@ -89,7 +115,14 @@ impl LabelTags {
     }
 
     pub fn from_pretrained(repo_id: &str) -> Result<Self, TaggerError> {
-        let csv_path = TagCSVFile::new(repo_id).get()?;
+        Self::from_pretrained_with_options(repo_id, &HfOptions::from_env())
+    }
+
+    pub fn from_pretrained_with_options(
+        repo_id: &str,
+        options: &HfOptions,
+    ) -> Result<Self, TaggerError> {
+        let csv_path = TagCSVFile::new(repo_id).get_with_options(options)?;
         Self::load(csv_path)
     }
 
@@ -121,6 +154,13 @@ impl LabelTags {
             .collect::<Result<Vec<HashMap<String, f32>>, TaggerError>>()
     }
 
+    /// Get the tags in the order of the model output
+    pub fn tags_in_order(&self) -> Vec<&Tag> {
+        (0..self.total_tags)
+            .filter_map(|idx| self.idx2tag.get(&idx))
+            .collect()
+    }
+
     pub fn label2tag(&self) -> &HashMap<String, Tag> {
         &self.label2tag
     }
@@ -136,7 +176,7 @@ mod test {
 
     use super::*;
 
-    use crate::file::{HfFile, TagCSVFile};
+    use crate::file::{HfFile, TagCSVFile, TempDir};
 
     #[test]
     fn test_load_tags() {
@@ -196,4 +236,26 @@ mod test {
         let pairs = tags.create_probality_pairs(random_prob);
         assert!(pairs.is_err());
     }
+
+    #[test]
+    fn test_tags_in_order() {
+        let temp = TempDir::new("tags-in-order");
+        let csv_path = temp.path().join("selected_tags.csv");
+        std::fs::write(
+            &csv_path,
+            "tag_id,name,category,count\n\
+             9999999,general,9,807690\n\
+             470575,1girl,0,4703758\n\
+             1,hatsune_miku,4,100\n",
+        )
+        .unwrap();
+        let tags = LabelTags::load(&csv_path).unwrap();
+
+        let ordered = tags.tags_in_order();
+        assert_eq!(
+            ordered.iter().map(|tag| tag.name()).collect::<Vec<_>>(),
+            vec!["general", "1girl", "hatsune_miku"]
+        );
+        assert_eq!(ordered[2].category(), TagCategory::Character);
+    }
 }
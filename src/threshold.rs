@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use crate::tags::TagCategory;
+
+/// Strategy to decide the threshold of the tag probabilities
+#[derive(Debug, Clone, PartialEq)]
+pub enum Threshold {
+    /// Keep the tags whose probability is greater than or equal to the value
+    Fixed(f32),
+    /// Maximum Cut Thresholding.
+    /// Cut at the largest gap between the sorted probabilities,
+    /// and at least at [`CHARACTER_MCUT_FLOOR`] for the character tags.
+    MCut,
+    /// Use a different threshold for each category.
+    /// Categories not specified are not filtered.
+    PerCategory(HashMap<TagCategory, Threshold>),
+}
+
+/// Default threshold for the general tags, same as the reference wd-tagger Space
+pub const DEFAULT_GENERAL_THRESHOLD: f32 = 0.35;
+
+/// Default threshold for the character tags, same as the reference wd-tagger Space
+pub const DEFAULT_CHARACTER_THRESHOLD: f32 = 0.85;
+
+/// Minimum MCut threshold of the character tags, same as the reference wd-tagger Space
+pub const CHARACTER_MCUT_FLOOR: f32 = 0.15;
+
+impl Default for Threshold {
+    /// Fixed thresholds for the general and character tags, and keep all the rating tags.
+    /// Artist and copyright tags use the character threshold, and meta tags use the general one.
+    fn default() -> Self {
+        Self::PerCategory(HashMap::from([
+            (
+                TagCategory::General,
+                Threshold::Fixed(DEFAULT_GENERAL_THRESHOLD),
+            ),
+            (
+                TagCategory::Character,
+                Threshold::Fixed(DEFAULT_CHARACTER_THRESHOLD),
+            ),
+            (
+                TagCategory::Artist,
+                Threshold::Fixed(DEFAULT_CHARACTER_THRESHOLD),
+            ),
+            (
+                TagCategory::Copyright,
+                Threshold::Fixed(DEFAULT_CHARACTER_THRESHOLD),
+            ),
+            (
+                TagCategory::Meta,
+                Threshold::Fixed(DEFAULT_GENERAL_THRESHOLD),
+            ),
+        ]))
+    }
+}
+
+impl Threshold {
+    /// Replace the threshold of the category, keeping the thresholds of the other categories.
+    pub fn with_category(self, category: TagCategory, threshold: Threshold) -> Self {
+        let mut thresholds = match self {
+            Threshold::PerCategory(thresholds) => thresholds,
+            uniform => TagCategory::all()
+                .into_iter()
+                .map(|category| (category, uniform.clone()))
+                .collect(),
+        };
+        thresholds.insert(category, threshold);
+
+        Threshold::PerCategory(thresholds)
+    }
+
+    /// Get the threshold applied to the category, `None` if the category is not filtered.
+    pub fn for_category(&self, category: &TagCategory) -> Option<&Threshold> {
+        match self {
+            Threshold::PerCategory(thresholds) => thresholds
+                .get(category)
+                .and_then(|threshold| threshold.for_category(category)),
+            _ => Some(self),
+        }
+    }
+
+    /// Compute the threshold value for the probabilities of the category.
+    /// Returns `None` if the category is not filtered.
+    pub fn compute(&self, category: &TagCategory, probs: &[f32]) -> Option<f32> {
+        match self.for_category(category)? {
+            Threshold::Fixed(threshold) => Some(*threshold),
+            Threshold::MCut => match category {
+                TagCategory::Character => Some(mcut_threshold(probs).max(CHARACTER_MCUT_FLOOR)),
+                _ => Some(mcut_threshold(probs)),
+            },
+            Threshold::PerCategory(_) => None,
+        }
+    }
+}
+
+/// Maximum Cut Thresholding (MCut).
+/// Ref: https://huggingface.co/spaces/SmilingWolf/wd-tagger/blob/main/app.py#L75-L88
+pub fn mcut_threshold(probs: &[f32]) -> f32 {
+    if probs.len() < 2 {
+        // no gap to cut, keep everything
+        return 0.0;
+    }
+
+    let mut sorted = probs.to_vec();
+    sorted.sort_by(|a, b| b.total_cmp(a));
+
+    let (t, _) = sorted
+        .windows(2)
+        .map(|pair| pair[0] - pair[1])
+        .enumerate()
+        .fold((0, f32::MIN), |(max_idx, max_dif), (idx, dif)| {
+            if dif > max_dif {
+                (idx, dif)
+            } else {
+                (max_idx, max_dif)
+            }
+        });
+
+    (sorted[t] + sorted[t + 1]) / 2.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mcut_threshold() {
+        let probs = vec![0.1, 0.95, 0.2, 0.9, 0.15];
+        let threshold = mcut_threshold(&probs);
+        assert!((threshold - 0.55).abs() < 1e-6);
+
+        let kept = probs.iter().filter(|&&p| p >= threshold).count();
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn test_mcut_threshold_few() {
+        assert_eq!(mcut_threshold(&[]), 0.0);
+        assert_eq!(mcut_threshold(&[0.7]), 0.0);
+    }
+
+    #[test]
+    fn test_character_mcut_floor() {
+        let threshold = Threshold::MCut;
+        // a single dominant character, as in the Space
+        let probs = [0.98, 0.12, 0.1, 0.05];
+        let value = threshold.compute(&TagCategory::Character, &probs).unwrap();
+        assert!((value - 0.55).abs() < 1e-6);
+
+        // the gap midpoint of the noise is below the floor
+        let probs = [0.12, 0.02, 0.01];
+        let value = threshold.compute(&TagCategory::Character, &probs).unwrap();
+        assert_eq!(value, CHARACTER_MCUT_FLOOR);
+        assert!(probs.iter().all(|&p| p < value));
+
+        // the general tags have no floor
+        let value = threshold.compute(&TagCategory::General, &probs).unwrap();
+        assert!((value - 0.07).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_per_category_threshold() {
+        let threshold = Threshold::PerCategory(HashMap::from([
+            (TagCategory::General, Threshold::Fixed(0.35)),
+            (TagCategory::Character, Threshold::MCut),
+        ]));
+
+        assert_eq!(
+            threshold.compute(&TagCategory::General, &[0.1, 0.9]),
+            Some(0.35)
+        );
+        assert_eq!(
+            threshold.compute(&TagCategory::Character, &[0.1, 0.9]),
+            Some(0.5)
+        );
+        assert_eq!(threshold.compute(&TagCategory::Rating, &[0.1, 0.9]), None);
+
+        let fixed = Threshold::Fixed(0.5);
+        assert_eq!(fixed.compute(&TagCategory::Rating, &[0.1, 0.9]), Some(0.5));
+    }
+
+    #[test]
+    fn test_with_category_threshold() {
+        let threshold = Threshold::default().with_category(TagCategory::Character, Threshold::MCut);
+        assert_eq!(
+            threshold.for_category(&TagCategory::General),
+            Some(&Threshold::Fixed(DEFAULT_GENERAL_THRESHOLD))
+        );
+        assert_eq!(
+            threshold.for_category(&TagCategory::Character),
+            Some(&Threshold::MCut)
+        );
+        assert_eq!(threshold.for_category(&TagCategory::Rating), None);
+
+        let threshold =
+            Threshold::Fixed(0.5).with_category(TagCategory::General, Threshold::Fixed(0.2));
+        assert_eq!(
+            threshold.for_category(&TagCategory::General),
+            Some(&Threshold::Fixed(0.2))
+        );
+        assert_eq!(
+            threshold.for_category(&TagCategory::Rating),
+            Some(&Threshold::Fixed(0.5))
+        );
+    }
+}